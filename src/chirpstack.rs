@@ -1,7 +1,8 @@
 use crate::serde_json;
 use crate::user_config::Config;
 use crate::utils::gen_hex::{
-    get_rand_app_key, get_rand_dev_eui, get_rand_hex_str, verify_app_key, verify_dev_eui,
+    get_rand_app_key, get_rand_dev_addr, get_rand_dev_eui, get_rand_hex_str, verify_app_key,
+    verify_dev_eui,
 };
 use clap::{Parser, Subcommand};
 use log::{debug, error, info, log_enabled, warn, Level};
@@ -111,6 +112,25 @@ impl LoraDevice {
     }
 }
 
+/// The session of a device activated by personalization (ABP).
+/// LoRaWAN 1.0 has a single network session key.
+#[derive(Debug, Clone)]
+pub struct AbpSession {
+    pub dev_addr: String,
+    pub nwks_key: String,
+    pub apps_key: String,
+}
+
+impl AbpSession {
+    pub fn random() -> AbpSession {
+        AbpSession {
+            dev_addr: get_rand_dev_addr(),
+            nwks_key: get_rand_app_key(),
+            apps_key: get_rand_app_key(),
+        }
+    }
+}
+
 pub fn handle_chirpstack_api(cfg: &Config, command: &ApiCommands) {
     match command {
         ApiCommands::Post {
@@ -162,30 +182,71 @@ fn get_device_profiles(
 
 /// Do both post device and set the key of the device
 pub fn handle_post_device(cfg: &Config, device: &LoraDevice) {
+    register_device(&ureq::agent(), cfg, device).unwrap();
+}
+
+/// Post the device and then its key.
+/// `agent` can be shared between threads so that they reuse the connections to the API.
+pub fn register_device(
+    agent: &ureq::Agent,
+    cfg: &Config,
+    device: &LoraDevice,
+) -> Result<(), ureq::Error> {
     info!(
         "Device Info\nDevEUI: {0}\nAppKey: {1}\nName: {2}",
         device.dev_eui, device.app_key, device.name
     );
-    let msg = post_device(cfg, &device).unwrap();
+    let msg = post_device(agent, cfg, &device)?;
     debug!(
         "Response in post device:\n{}",
         serde_json::to_string_pretty(&msg).unwrap()
     );
-    let msg = post_appkey(cfg, &device).unwrap();
+    let msg = post_appkey(agent, cfg, &device)?;
     debug!(
         "Response in post appkey:\n{}",
         serde_json::to_string_pretty(&msg).unwrap()
     );
+    Ok(())
+}
+
+/// Post the device and then activate it with the ABP session.
+/// The device profile must have join (OTAA) disabled.
+pub fn activate_device(
+    agent: &ureq::Agent,
+    cfg: &Config,
+    device: &LoraDevice,
+    session: &AbpSession,
+) -> Result<(), ureq::Error> {
+    info!(
+        "Device Info\nDevEUI: {0}\nDevAddr: {1}\nNwkSKey: {2}\nAppSKey: {3}\nName: {4}",
+        device.dev_eui, session.dev_addr, session.nwks_key, session.apps_key, device.name
+    );
+    let msg = post_device(agent, cfg, device)?;
+    debug!(
+        "Response in post device:\n{}",
+        serde_json::to_string_pretty(&msg).unwrap()
+    );
+    let msg = post_activation(agent, cfg, device, session)?;
+    debug!(
+        "Response in post activation:\n{}",
+        serde_json::to_string_pretty(&msg).unwrap()
+    );
+    Ok(())
 }
 
-fn post_device(cfg: &Config, device: &LoraDevice) -> Result<serde_json::Value, ureq::Error> {
+fn post_device(
+    agent: &ureq::Agent,
+    cfg: &Config,
+    device: &LoraDevice,
+) -> Result<serde_json::Value, ureq::Error> {
     // chirpstack doesn't allow post a device and appkey at the same time
     // so we have to move appKey out of the device struct
     let mut clone_device = device.clone();
     clone_device.app_key = "".into();
     let request = json!({ "device": clone_device });
     debug!("POST:\n{}", serde_json::to_string_pretty(&request).unwrap());
-    let msg: serde_json::Value = ureq::post(&format!("{}/devices", cfg.url))
+    let msg: serde_json::Value = agent
+        .post(&format!("{}/devices", cfg.url))
         .set("Authorization", &format!("Bearer {}", cfg.token))
         .query("applicationID", &cfg.application_id.clone())
         .send_json(request)?
@@ -193,18 +254,50 @@ fn post_device(cfg: &Config, device: &LoraDevice) -> Result<serde_json::Value, u
     Ok(msg)
 }
 
-fn post_appkey(cfg: &Config, device: &LoraDevice) -> Result<serde_json::Value, ureq::Error> {
+fn post_appkey(
+    agent: &ureq::Agent,
+    cfg: &Config,
+    device: &LoraDevice,
+) -> Result<serde_json::Value, ureq::Error> {
     let request = json!({
         "deviceKeys":{
             "nwkKey": device.app_key,
             "devEUI": device.dev_eui
     }});
     debug!("POST:\n{}", serde_json::to_string_pretty(&request).unwrap());
-    let msg: serde_json::Value =
-        ureq::post(&format!("{0}/devices/{1}/keys", cfg.url, device.dev_eui))
-            .set("Authorization", &format!("Bearer {}", cfg.token))
-            .query("applicationID", &cfg.application_id.clone())
-            .send_json(request)?
-            .into_json()?;
+    let msg: serde_json::Value = agent
+        .post(&format!("{0}/devices/{1}/keys", cfg.url, device.dev_eui))
+        .set("Authorization", &format!("Bearer {}", cfg.token))
+        .query("applicationID", &cfg.application_id.clone())
+        .send_json(request)?
+        .into_json()?;
+    Ok(msg)
+}
+
+fn post_activation(
+    agent: &ureq::Agent,
+    cfg: &Config,
+    device: &LoraDevice,
+    session: &AbpSession,
+) -> Result<serde_json::Value, ureq::Error> {
+    // the three network keys of LoRaWAN 1.1 are all the NwkSKey in 1.0
+    let request = json!({
+        "deviceActivation": {
+            "devEUI": device.dev_eui,
+            "devAddr": session.dev_addr,
+            "appSKey": session.apps_key,
+            "nwkSEncKey": session.nwks_key,
+            "sNwkSIntKey": session.nwks_key,
+            "fNwkSIntKey": session.nwks_key,
+            "fCntUp": 0,
+            "nFCntDown": 0,
+            "aFCntDown": 0
+    }});
+    debug!("POST:\n{}", serde_json::to_string_pretty(&request).unwrap());
+    let msg: serde_json::Value = agent
+        .post(&format!("{0}/devices/{1}/activate", cfg.url, device.dev_eui))
+        .set("Authorization", &format!("Bearer {}", cfg.token))
+        .send_json(request)?
+        .into_json()?;
     Ok(msg)
 }
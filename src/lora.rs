@@ -0,0 +1,65 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// How the device gets its session keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+pub enum JoinMode {
+    /// Over-The-Air Activation
+    Otaa,
+    /// Activation By Personalization
+    Abp,
+}
+
+/// LoRaWAN regional parameters supported by RAK811
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Region {
+    Eu433,
+    Cn470,
+    In865,
+    Eu868,
+    Us915,
+    Au915,
+    Kr920,
+    As923,
+}
+
+impl Region {
+    /// The name used by the LoRa Alliance, e.g. `EU868`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Region::Eu433 => "EU433",
+            Region::Cn470 => "CN470",
+            Region::In865 => "IN865",
+            Region::Eu868 => "EU868",
+            Region::Us915 => "US915",
+            Region::Au915 => "AU915",
+            Region::Kr920 => "KR920",
+            Region::As923 => "AS923",
+        }
+    }
+
+    /// The largest application payload (N) at data rate `dr`, as in the band
+    /// definitions of ChirpStack. AS923 is without dwell time limits. `None`
+    /// if the region has no such data rate.
+    pub fn max_payload(&self, dr: u8) -> Option<usize> {
+        let sizes: &[usize] = match self {
+            Region::Eu433 | Region::Eu868 => &[51, 51, 51, 115, 242, 242, 242, 242],
+            Region::Cn470 | Region::Kr920 => &[51, 51, 51, 115, 242, 242],
+            Region::In865 => &[51, 51, 51, 115, 242, 242, 0, 242],
+            Region::Us915 => &[11, 53, 125, 242, 242, 0, 0, 0, 53, 129, 242, 242, 242, 242],
+            Region::Au915 => &[51, 51, 51, 115, 242, 242, 242, 0, 53, 129, 242, 242, 242, 242],
+            Region::As923 => &[51, 51, 115, 115, 242, 242, 242, 242],
+        };
+        // 0 marks the data rates reserved for future use
+        sizes.get(dr as usize).copied().filter(|size| *size > 0)
+    }
+}
+
+/// LoRaWAN device class
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+pub enum Class {
+    A,
+    B,
+    C,
+}
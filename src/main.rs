@@ -1,16 +1,23 @@
 use clap::{Parser, Subcommand};
 use env_logger::{Builder, Target};
+use lora::JoinMode;
 use log::{debug, error, info, log_enabled, warn, Level};
-use serialport;
 use std::env;
+use std::path::PathBuf;
 use std::{io::Read, time::Duration};
 use ureq::serde_json;
 use user_config::{read_config, Config};
 
 mod chirpstack;
+mod lora;
+mod payload;
+mod ports;
+mod provision;
 mod serial;
+mod sim;
 mod user_config;
 mod utils;
+mod watch;
 
 extern crate confy;
 
@@ -19,6 +26,10 @@ extern crate confy;
 #[clap(name = "laser-utils")]
 #[clap(about = "A tool for managing your LoRa devices and ChirpStack API")]
 struct Cli {
+    /// Record everything sent to and received from the serial ports into
+    /// timestamped files in this directory
+    #[clap(long, global = true)]
+    transcript: Option<PathBuf>,
     #[clap(subcommand)]
     command: Commands,
 }
@@ -26,19 +37,40 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// List Serial ports
-    // No need params
-    Ls,
+    Ls {
+        /// Only list the USB ports with this vendor ID (hex)
+        #[clap(long, value_parser = ports::parse_usb_id)]
+        vid: Option<u16>,
+        /// Only list the USB ports with this product ID (hex)
+        #[clap(long, value_parser = ports::parse_usb_id)]
+        pid: Option<u16>,
+        /// Detect the LoRa module on each port, and read its firmware and DevEUI
+        #[clap(long, action)]
+        probe: bool,
+        /// How to print the ports
+        #[clap(short, long, value_enum, default_value_t = ports::LsFormat::Table)]
+        format: ports::LsFormat,
+    },
     /// Send at command to serial ports
     At {
         /// The path of serial port
         #[clap(short, long)]
         path: String,
-        /// Baudrate
-        #[clap(short, long, default_value_t = 115200)]
-        baud: u32,
+        /// Baudrate. Detected automatically if not set.
+        #[clap(short, long)]
+        baud: Option<u32>,
+        /// The LoRa module attached to the serial port. Detected automatically if not set.
+        #[clap(short, long, value_enum)]
+        module: Option<serial::ModuleKind>,
         #[clap(subcommand)]
         command: serial::at::AtCommands,
     },
+    /// Detect the LoRa module and baudrate of a serial port
+    Probe {
+        /// The path of serial port
+        #[clap(short, long)]
+        path: String,
+    },
     /// Send request to ChirpStack API. The infomation of API will be read from config file.
     /// Please make sure the config file is correctly set.
     Api {
@@ -48,12 +80,16 @@ enum Commands {
     /// Config the device automatically, hopefully.
     /// Please make sure the device is connected to your computer.
     All {
-        /// The path of serial port
+        /// The path of serial port. Repeat it, or use a glob like `/dev/ttyUSB*`,
+        /// to provision several devices at the same time.
+        #[clap(short, long, required = true, num_args = 1..)]
+        path: Vec<String>,
+        /// Baudrate. Detected automatically if not set.
         #[clap(short, long)]
-        path: String,
-        /// Baudrate
-        #[clap(short, long, default_value_t = 115200)]
-        baud: u32,
+        baud: Option<u32>,
+        /// The LoRa module attached to the serial port. Detected automatically if not set.
+        #[clap(short, long, value_enum)]
+        module: Option<serial::ModuleKind>,
         /// The device name. If not specified, the name will be generated randomly.
         #[clap(short, long, default_value = "")]
         name: String,
@@ -66,25 +102,74 @@ enum Commands {
         /// Set the app key (128 bit hex). if not set, the app key will be generated randomly.
         #[clap(long, default_value = "")]
         app_key: String,
+        /// Read the DevEUI programmed into the module by the factory, write it as the
+        /// DevEUI and register it, instead of a new one.
+        #[clap(long, action, conflicts_with = "dev_eui")]
+        hw_dev_eui: bool,
+        /// OTAA writes the app key. ABP writes a random DevAddr, NwkSKey and AppSKey
+        /// and activates the device, whose device profile must not support join.
+        #[clap(long, value_enum, default_value_t = JoinMode::Otaa)]
+        activation: JoinMode,
+    },
+    /// Wait for new serial ports and provision each of them like `all`.
+    /// Every board gets a random name, DevEUI and app key.
+    Watch {
+        /// Only watch the USB ports with this vendor ID (hex)
+        #[clap(long, value_parser = ports::parse_usb_id)]
+        vid: Option<u16>,
+        /// Only watch the USB ports with this product ID (hex)
+        #[clap(long, value_parser = ports::parse_usb_id)]
+        pid: Option<u16>,
+        /// Baudrate. Detected automatically if not set.
+        #[clap(short, long)]
+        baud: Option<u32>,
+        /// The LoRa module attached to the serial ports. Detected automatically if not set.
+        #[clap(short, long, value_enum)]
+        module: Option<serial::ModuleKind>,
+        /// The device description
+        #[clap(short, long, default_value = "a test device")]
+        description: String,
+        /// Write and register the factory DevEUI of each module instead of a new one
+        #[clap(long, action)]
+        hw_dev_eui: bool,
+        /// How the devices are activated, see `all`
+        #[clap(long, value_enum, default_value_t = JoinMode::Otaa)]
+        activation: JoinMode,
+        /// How often to look for new ports, in milliseconds
+        #[clap(long, default_value_t = 500)]
+        interval: u64,
+    },
+    /// Simulate a RAK811 on a pseudo-terminal, to test `at` and `all` without hardware
+    Sim {
+        /// Also make the pseudo-terminal available at this path
+        #[clap(long)]
+        link: Option<PathBuf>,
+        /// How long `at+join` takes, in milliseconds
+        #[clap(long, default_value_t = 1000)]
+        join_delay: u64,
+        /// Fail the first N joins with `ERROR: 99`
+        #[clap(long, default_value_t = 0)]
+        join_failures: u32,
+        /// Always fail the commands starting with a prefix, e.g. `at+set_config=lora:app_key=3`
+        #[clap(long, value_parser = sim::parse_fail)]
+        fail: Vec<(String, u16)>,
+        /// The probability (0 to 1) that any command fails with `ERROR: 80`
+        #[clap(long, default_value_t = 0.0)]
+        error_rate: f64,
+        /// Answer every uplink with this downlink, e.g. `10:CAFE`
+        #[clap(long, value_parser = sim::parse_downlink)]
+        downlink: Option<(u8, String)>,
     }, // TODO: Write config file by subcommand
        // /// A convenient way to set config file
        // #[clap(setting(AppSettings::ArgRequiredElseHelp))]
        // Config
 }
 
-fn main() {
-    // Set the default log level to debug
-    if env::var("RUST_LOG").is_err() {
-        env::set_var("RUST_LOG", "debug")
-    }
-    // Output the log to STDOUT
-    let mut builder = Builder::from_default_env();
-    builder.target(Target::Stdout);
-    builder.init();
-    let args = Cli::parse();
+/// Only the commands talking to ChirpStack need the config
+fn load_config() -> Config {
     let app_name = "chirpstack-utils";
     let cfg = read_config(app_name.to_string());
-    let cfg = match cfg {
+    match cfg {
         Ok(cfg) => cfg,
         Err(e) => {
             let file = confy::get_configuration_file_path(&app_name, None).unwrap();
@@ -94,43 +179,149 @@ fn main() {
             );
             panic!("{}", e)
         }
-    };
+    }
+}
 
+fn main() {
+    // Set the default log level to debug
+    if env::var("RUST_LOG").is_err() {
+        env::set_var("RUST_LOG", "debug")
+    }
+    // Output the log to STDOUT
+    let mut builder = Builder::from_default_env();
+    builder.target(Target::Stdout);
+    builder.init();
+    let args = Cli::parse();
+    if let Some(dir) = &args.transcript {
+        serial::transcript::record_to(dir.clone());
+    }
     match &args.command {
-        Commands::Ls => {
-            let ports = serialport::available_ports().unwrap();
-            for port in ports {
-                println!("{}", port.port_name);
-            }
-        }
+        Commands::Ls {
+            vid,
+            pid,
+            probe,
+            format,
+        } => ports::handle_ls(*vid, *pid, *probe, *format),
         Commands::At {
             path,
             baud,
+            module,
             command,
-        } => serial::at::handle_at_commands(path, baud, command),
-        Commands::Api { command } => chirpstack::handle_chirpstack_api(&cfg, command),
+        } => {
+            if let Err(e) = serial::at::handle_at_commands(path, baud, module, command) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Probe { path } => {
+            let modules = serial::probe::MODULES;
+            let bauds = serial::probe::BAUD_RATES;
+            match serial::probe::probe(path, &modules, &bauds) {
+                Some(found) => info!(
+                    "Module: {:?}\nBaudrate: {}\nFirmware: {}",
+                    found.module, found.baud, found.version
+                ),
+                None => error!("No module answered on {}", path),
+            }
+        }
+        Commands::Api { command } => chirpstack::handle_chirpstack_api(&load_config(), command),
         Commands::All {
             path,
             baud,
+            module,
             name,
             description,
             dev_eui,
             app_key,
+            hw_dev_eui,
+            activation,
         } => {
-            fn serial_builder(path:&str, baud:u32) -> Box<dyn serialport::SerialPort> {
-                let serial = serialport::new(path, baud)
-                    .timeout(Duration::new(5, 0))
-                    .open()
-                    .expect("Failed to open serial port");
-                serial
+            if *activation == JoinMode::Abp && !app_key.is_empty() {
+                error!("--app-key can't be used with --activation abp");
+                std::process::exit(1);
+            }
+            let opts = provision::ProvisionOptions {
+                baud: *baud,
+                module: *module,
+                name: name.clone(),
+                description: description.clone(),
+                dev_eui: dev_eui.clone(),
+                app_key: app_key.clone(),
+                hw_dev_eui: *hw_dev_eui,
+                activation: *activation,
+            };
+            let cfg = load_config();
+            let paths = provision::expand_paths(path);
+            if paths.len() == 1 {
+                if let Err(e) = provision::provision(&ureq::agent(), &cfg, &paths[0], &opts) {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+                return;
+            }
+            if paths.is_empty() {
+                error!("No serial port to provision");
+                std::process::exit(1);
+            }
+            if !name.is_empty() || !dev_eui.is_empty() || !app_key.is_empty() {
+                error!("--name, --dev-eui and --app-key can only be used with a single port");
+                std::process::exit(1);
+            }
+            let results = provision::provision_many(&cfg, &paths, &opts);
+            let mut failed = 0;
+            for (path, result) in paths.iter().zip(&results) {
+                match result {
+                    Ok(device) => println!("PASS {} DevEUI {}", path, device.dev_eui),
+                    Err(e) => {
+                        failed += 1;
+                        println!("FAIL {}: {}", path, e);
+                    }
+                }
+            }
+            println!("Passed: {}, Failed: {}", paths.len() - failed, failed);
+            if failed > 0 {
+                std::process::exit(1);
             }
-            let device = chirpstack::LoraDevice::new(&cfg, app_key, dev_eui, description, name);
-            serial::at_dev_eui(serial_builder(path, *baud), &device.dev_eui);
-            // open a new serial port to avoid ownership problem
-            serial::at_app_key(serial_builder(path, *baud), &device.app_key);
-            info!("The device is configured successfully, maybe");
-            chirpstack::handle_post_device(&cfg, &device);
-            info!("The info has been updated successfully, maybe");
+        }
+        Commands::Sim {
+            link,
+            join_delay,
+            join_failures,
+            fail,
+            error_rate,
+            downlink,
+        } => {
+            let opts = sim::SimOptions {
+                join_delay: Duration::from_millis(*join_delay),
+                join_failures: *join_failures,
+                fail: fail.clone(),
+                error_rate: *error_rate,
+                downlink: downlink.clone(),
+            };
+            sim::handle_sim(opts, link.clone())
+        }
+        Commands::Watch {
+            vid,
+            pid,
+            baud,
+            module,
+            description,
+            hw_dev_eui,
+            activation,
+            interval,
+        } => {
+            let opts = provision::ProvisionOptions {
+                baud: *baud,
+                module: *module,
+                name: String::new(),
+                description: description.clone(),
+                dev_eui: String::new(),
+                app_key: String::new(),
+                hw_dev_eui: *hw_dev_eui,
+                activation: *activation,
+            };
+            let cfg = load_config();
+            watch::handle_watch(&cfg, *vid, *pid, Duration::from_millis(*interval), &opts)
         }
     }
 }
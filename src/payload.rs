@@ -0,0 +1,210 @@
+use crate::lora::Region;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use clap::ValueEnum;
+use std::fmt;
+use std::io;
+use ureq::serde_json;
+
+pub mod lpp;
+
+/// How the input of `at send` becomes the payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Encoding {
+    /// The input as is, e.g. UTF-8 text or the bytes of a file
+    Text,
+    /// Hex digits
+    Hex,
+    /// Standard base64
+    Base64,
+    /// JSON, re-encoded as CBOR
+    Cbor,
+    /// JSON, re-encoded as MessagePack
+    Msgpack,
+}
+
+#[derive(Debug)]
+pub enum PayloadError {
+    Hex,
+    Base64(base64::DecodeError),
+    Json(serde_json::Error),
+    Cbor(ciborium::ser::Error<io::Error>),
+    Msgpack(rmp_serde::encode::Error),
+    /// Longer than the region allows at the data rate
+    TooLong {
+        len: usize,
+        max: usize,
+        region: Region,
+        dr: u8,
+    },
+}
+
+impl fmt::Display for PayloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PayloadError::Hex => write!(f, "the input is not hex"),
+            PayloadError::Base64(e) => write!(f, "the input is not base64: {}", e),
+            PayloadError::Json(e) => write!(f, "the input is not JSON: {}", e),
+            PayloadError::Cbor(e) => write!(f, "failed to encode CBOR: {}", e),
+            PayloadError::Msgpack(e) => write!(f, "failed to encode MessagePack: {}", e),
+            PayloadError::TooLong {
+                len,
+                max,
+                region,
+                dr,
+            } => write!(
+                f,
+                "the payload is {} bytes, {} allows {} at DR{}",
+                len,
+                region.as_str(),
+                max,
+                dr
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PayloadError {}
+
+/// `None` unless `hex` is an even number of hex digits
+pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [high, low] => {
+                let digit = |c: &u8| (*c as char).to_digit(16);
+                Some((digit(high)? * 16 + digit(low)?) as u8)
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Turn `input` into the payload. Whitespace around hex and base64 is ignored.
+pub fn encode(input: &[u8], encoding: Encoding) -> Result<Vec<u8>, PayloadError> {
+    let text = || String::from_utf8_lossy(input).trim().to_string();
+    match encoding {
+        Encoding::Text => Ok(input.to_vec()),
+        Encoding::Hex => decode_hex(&text()).ok_or(PayloadError::Hex),
+        Encoding::Base64 => STANDARD.decode(text()).map_err(PayloadError::Base64),
+        Encoding::Cbor => {
+            let value: serde_json::Value =
+                serde_json::from_slice(input).map_err(PayloadError::Json)?;
+            let mut payload = Vec::new();
+            ciborium::ser::into_writer(&value, &mut payload).map_err(PayloadError::Cbor)?;
+            Ok(payload)
+        }
+        Encoding::Msgpack => {
+            let value: serde_json::Value =
+                serde_json::from_slice(input).map_err(PayloadError::Json)?;
+            rmp_serde::to_vec(&value).map_err(PayloadError::Msgpack)
+        }
+    }
+}
+
+/// Make sure `len` bytes fit in a single uplink. Unknown data rates are not checked.
+pub fn check_length(len: usize, region: Region, dr: u8) -> Result<(), PayloadError> {
+    match region.max_payload(dr) {
+        Some(max) if len > max => Err(PayloadError::TooLong {
+            len,
+            max,
+            region,
+            dr,
+        }),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JSON: &str = r#"{"temperature": 21.5, "ok": true}"#;
+
+    fn json() -> serde_json::Value {
+        serde_json::from_str(JSON).unwrap()
+    }
+
+    #[test]
+    fn text_and_hex() {
+        assert_eq!(encode(b" Hi\n", Encoding::Text).unwrap(), b" Hi\n");
+        assert_eq!(encode(b"0aFF\n", Encoding::Hex).unwrap(), [0x0A, 0xFF]);
+        for input in ["0aF", "0g", "0a ff"] {
+            assert!(matches!(
+                encode(input.as_bytes(), Encoding::Hex),
+                Err(PayloadError::Hex)
+            ));
+        }
+    }
+
+    #[test]
+    fn base64() {
+        assert_eq!(
+            encode(b"3q2+7w==\n", Encoding::Base64).unwrap(),
+            [0xDE, 0xAD, 0xBE, 0xEF]
+        );
+        assert!(matches!(
+            encode(b"3q2+7w", Encoding::Base64),
+            Err(PayloadError::Base64(_))
+        ));
+    }
+
+    #[test]
+    fn cbor() {
+        let payload = encode(JSON.as_bytes(), Encoding::Cbor).unwrap();
+        // a map of two entries
+        assert_eq!(payload[0], 0xA2);
+        let value: serde_json::Value = ciborium::de::from_reader(&payload[..]).unwrap();
+        assert_eq!(value, json());
+    }
+
+    #[test]
+    fn msgpack() {
+        let payload = encode(JSON.as_bytes(), Encoding::Msgpack).unwrap();
+        // a fixmap of two entries
+        assert_eq!(payload[0], 0x82);
+        let value: serde_json::Value = rmp_serde::from_slice(&payload).unwrap();
+        assert_eq!(value, json());
+    }
+
+    #[test]
+    fn not_json() {
+        for encoding in [Encoding::Cbor, Encoding::Msgpack] {
+            assert!(matches!(
+                encode(b"{temperature: 21.5}", encoding),
+                Err(PayloadError::Json(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn check_length_at_the_boundary() {
+        check_length(11, Region::Us915, 0).unwrap();
+        assert!(matches!(
+            check_length(12, Region::Us915, 0),
+            Err(PayloadError::TooLong {
+                len: 12,
+                max: 11,
+                region: Region::Us915,
+                dr: 0
+            })
+        ));
+        check_length(51, Region::Eu868, 0).unwrap();
+        assert!(check_length(52, Region::Eu868, 0).is_err());
+        check_length(242, Region::Eu868, 5).unwrap();
+        assert!(check_length(243, Region::Eu868, 5).is_err());
+        // SF10 and SF9 of AS923 without dwell time limits
+        check_length(115, Region::As923, 2).unwrap();
+        assert!(check_length(116, Region::As923, 2).is_err());
+        check_length(115, Region::As923, 3).unwrap();
+        assert!(check_length(116, Region::As923, 3).is_err());
+        check_length(242, Region::As923, 4).unwrap();
+    }
+
+    #[test]
+    fn check_length_of_unknown_data_rates() {
+        // reserved for future use
+        check_length(1000, Region::Us915, 5).unwrap();
+        check_length(1000, Region::Eu868, 15).unwrap();
+    }
+}
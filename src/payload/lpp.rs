@@ -0,0 +1,320 @@
+use clap::ValueEnum;
+use std::fmt;
+
+/// The data types of Cayenne LPP
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LppType {
+    DigitalInput,
+    DigitalOutput,
+    /// Signed, 0.01
+    AnalogInput,
+    /// Signed, 0.01
+    AnalogOutput,
+    /// Lux
+    Illuminance,
+    Presence,
+    /// °C, 0.1
+    Temperature,
+    /// %, 0.5
+    Humidity,
+    /// x, y and z in G, 0.001
+    Accelerometer,
+    /// hPa, 0.1
+    Barometer,
+    /// x, y and z in °/s, 0.01
+    Gyrometer,
+    /// Latitude and longitude in °, 0.0001, and altitude in m, 0.01
+    Gps,
+}
+
+impl LppType {
+    pub fn code(self) -> u8 {
+        match self {
+            LppType::DigitalInput => 0,
+            LppType::DigitalOutput => 1,
+            LppType::AnalogInput => 2,
+            LppType::AnalogOutput => 3,
+            LppType::Illuminance => 101,
+            LppType::Presence => 102,
+            LppType::Temperature => 103,
+            LppType::Humidity => 104,
+            LppType::Accelerometer => 113,
+            LppType::Barometer => 115,
+            LppType::Gyrometer => 134,
+            LppType::Gps => 136,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<LppType> {
+        LppType::value_variants()
+            .iter()
+            .copied()
+            .find(|t| t.code() == code)
+    }
+
+    /// The size in bytes, the multiplier and the signedness of each value
+    fn fields(self) -> &'static [(usize, f64, bool)] {
+        match self {
+            LppType::DigitalInput | LppType::DigitalOutput | LppType::Presence => {
+                &[(1, 1.0, false)]
+            }
+            LppType::AnalogInput | LppType::AnalogOutput => &[(2, 100.0, true)],
+            LppType::Illuminance => &[(2, 1.0, false)],
+            LppType::Temperature => &[(2, 10.0, true)],
+            LppType::Humidity => &[(1, 2.0, false)],
+            LppType::Accelerometer => &[(2, 1000.0, true); 3],
+            LppType::Barometer => &[(2, 10.0, false)],
+            LppType::Gyrometer => &[(2, 100.0, true); 3],
+            LppType::Gps => &[(3, 10000.0, true), (3, 10000.0, true), (3, 100.0, true)],
+        }
+    }
+
+    /// The name taken by `parse_reading`, e.g. `analog-input`
+    pub fn name(self) -> String {
+        self.to_possible_value()
+            .map(|v| v.get_name().to_string())
+            .unwrap_or_default()
+    }
+
+    fn size(self) -> usize {
+        self.fields().iter().map(|(size, _, _)| size).sum()
+    }
+}
+
+/// A value of a sensor, e.g. `1:temperature:21.5`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reading {
+    pub channel: u8,
+    pub kind: LppType,
+    /// As many as the type has, e.g. three for GPS
+    pub values: Vec<f64>,
+}
+
+impl fmt::Display for Reading {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let values: Vec<String> = self.values.iter().map(|v| v.to_string()).collect();
+        write!(
+            f,
+            "{}:{}:{}",
+            self.channel,
+            self.kind.name(),
+            values.join(",")
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum LppError {
+    /// The value doesn't fit in the bytes of its type
+    OutOfRange {
+        channel: u8,
+        value: f64,
+    },
+    UnknownType {
+        channel: u8,
+        code: u8,
+    },
+    /// The payload ends in the middle of a reading
+    Truncated,
+}
+
+impl fmt::Display for LppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LppError::OutOfRange { channel, value } => {
+                write!(f, "channel {}: {} is out of range", channel, value)
+            }
+            LppError::UnknownType { channel, code } => {
+                write!(f, "channel {}: unknown type {}", channel, code)
+            }
+            LppError::Truncated => write!(f, "the payload is truncated"),
+        }
+    }
+}
+
+impl std::error::Error for LppError {}
+
+/// Parse `<channel>:<type>:<value>[,<value>...]`, e.g. `3:gps:52.3676,4.9041,10`
+pub fn parse_reading(s: &str) -> Result<Reading, String> {
+    let mut parts = s.splitn(3, ':');
+    let (channel, kind, values) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(channel), Some(kind), Some(values)) => (channel, kind, values),
+        _ => return Err(format!("expected channel:type:value, got {}", s)),
+    };
+    let channel = channel
+        .parse()
+        .map_err(|_| format!("invalid channel {}", channel))?;
+    let kind = LppType::from_str(kind, true).map_err(|_| format!("unknown type {}", kind))?;
+    let values = values
+        .split(',')
+        .map(|v| v.trim().parse().map_err(|_| format!("invalid value {}", v)))
+        .collect::<Result<Vec<f64>, String>>()?;
+    if values.len() != kind.fields().len() {
+        return Err(format!(
+            "{} takes {} value(s), got {}",
+            kind.name(),
+            kind.fields().len(),
+            values.len()
+        ));
+    }
+    Ok(Reading {
+        channel,
+        kind,
+        values,
+    })
+}
+
+/// Each reading is the channel, the type and the values in big endian
+pub fn encode(readings: &[Reading]) -> Result<Vec<u8>, LppError> {
+    let mut payload = Vec::new();
+    for reading in readings {
+        payload.push(reading.channel);
+        payload.push(reading.kind.code());
+        for (value, (size, multiplier, signed)) in reading.values.iter().zip(reading.kind.fields())
+        {
+            let raw = (value * multiplier).round() as i64;
+            let bits = 8 * *size as u32;
+            let (min, max) = if *signed {
+                (-(1 << (bits - 1)), (1 << (bits - 1)) - 1)
+            } else {
+                (0, (1 << bits) - 1)
+            };
+            if raw < min || raw > max {
+                return Err(LppError::OutOfRange {
+                    channel: reading.channel,
+                    value: *value,
+                });
+            }
+            payload.extend_from_slice(&raw.to_be_bytes()[8 - size..]);
+        }
+    }
+    Ok(payload)
+}
+
+/// The readings in `payload`, e.g. the data of a downlink
+pub fn decode(payload: &[u8]) -> Result<Vec<Reading>, LppError> {
+    let mut readings = Vec::new();
+    let mut rest = payload;
+    while !rest.is_empty() {
+        let (channel, code) = match rest {
+            [channel, code, ..] => (*channel, *code),
+            _ => return Err(LppError::Truncated),
+        };
+        let kind = LppType::from_code(code).ok_or(LppError::UnknownType { channel, code })?;
+        let mut data = rest.get(2..2 + kind.size()).ok_or(LppError::Truncated)?;
+        rest = &rest[2 + kind.size()..];
+        let mut values = Vec::new();
+        for (size, multiplier, signed) in kind.fields() {
+            let raw = data[..*size]
+                .iter()
+                .fold(0i64, |raw, byte| raw << 8 | *byte as i64);
+            // sign-extend from the size of the field
+            let raw = if *signed {
+                let shift = 64 - 8 * *size as u32;
+                raw << shift >> shift
+            } else {
+                raw
+            };
+            values.push(raw as f64 / multiplier);
+            data = &data[*size..];
+        }
+        readings.push(Reading {
+            channel,
+            kind,
+            values,
+        });
+    }
+    Ok(readings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(s: &str) -> Reading {
+        parse_reading(s).unwrap()
+    }
+
+    /// Encode `s`, check the bytes and decode them back
+    fn round_trip(s: &str, bytes: &[u8]) {
+        let readings = vec![reading(s)];
+        assert_eq!(encode(&readings).unwrap(), bytes, "{}", s);
+        assert_eq!(decode(bytes).unwrap(), readings, "{}", s);
+    }
+
+    #[test]
+    fn round_trip_readings() {
+        round_trip("1:temperature:21.5", &[0x01, 0x67, 0x00, 0xD7]);
+        round_trip("2:humidity:40", &[0x02, 0x68, 0x50]);
+        round_trip("4:analog-input:3.3", &[0x04, 0x02, 0x01, 0x4A]);
+        round_trip(
+            "3:gps:52.3676,4.9041,10",
+            &[
+                0x03, 0x88, 0x07, 0xFD, 0x9C, 0x00, 0xBF, 0x91, 0x00, 0x03, 0xE8,
+            ],
+        );
+    }
+
+    #[test]
+    fn round_trip_negative_values() {
+        round_trip("1:temperature:-4.1", &[0x01, 0x67, 0xFF, 0xD7]);
+        // the 3-byte fields of GPS are sign-extended
+        round_trip(
+            "3:gps:-33.8688,151.2093,-41",
+            &[
+                0x03, 0x88, 0xFA, 0xD5, 0x00, 0x17, 0x12, 0x9D, 0xFF, 0xEF, 0xFC,
+            ],
+        );
+    }
+
+    #[test]
+    fn several_readings() {
+        let readings = vec![reading("1:temperature:21.5"), reading("2:humidity:40")];
+        let payload = encode(&readings).unwrap();
+        assert_eq!(payload, [0x01, 0x67, 0x00, 0xD7, 0x02, 0x68, 0x50]);
+        assert_eq!(decode(&payload).unwrap(), readings);
+        assert_eq!(readings[0].to_string(), "1:temperature:21.5");
+    }
+
+    #[test]
+    fn out_of_range() {
+        for s in ["2:humidity:200", "2:humidity:-1", "1:temperature:4000"] {
+            assert!(
+                matches!(encode(&[reading(s)]), Err(LppError::OutOfRange { .. })),
+                "{}",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn decode_truncated() {
+        for payload in [&[0x01][..], &[0x01, 0x67, 0x00], &[0x03, 0x88, 0x07, 0xFD]] {
+            assert!(
+                matches!(decode(payload), Err(LppError::Truncated)),
+                "{:?}",
+                payload
+            );
+        }
+        assert!(matches!(
+            decode(&[0x05, 0x42, 0x00]),
+            Err(LppError::UnknownType {
+                channel: 5,
+                code: 0x42
+            })
+        ));
+    }
+
+    #[test]
+    fn parse_bad_readings() {
+        for s in [
+            "1:temperature",
+            "x:temperature:1",
+            "1:weight:1",
+            "1:temperature:a",
+            "3:gps:1,2",
+        ] {
+            assert!(parse_reading(s).is_err(), "{}", s);
+        }
+    }
+}
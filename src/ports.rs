@@ -0,0 +1,177 @@
+use crate::serial;
+use crate::serial::probe::{BAUD_RATES, MODULES};
+use crate::serial::ModuleKind;
+use clap::ValueEnum;
+use log::{debug, error};
+use serde::Serialize;
+use serialport::{SerialPortInfo, SerialPortType};
+use std::time::Duration;
+use ureq::serde_json;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum LsFormat {
+    Table,
+    Json,
+}
+
+/// A row of `ls`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PortDetail {
+    pub port: String,
+    /// `usb`, `pci`, `bluetooth` or `unknown`
+    #[serde(rename = "type")]
+    pub port_type: String,
+    /// USB vendor ID in hex
+    pub vid: Option<String>,
+    /// USB product ID in hex
+    pub pid: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+    /// Only filled when probed
+    pub module: Option<ModuleKind>,
+    pub baud: Option<u32>,
+    pub firmware: Option<String>,
+    pub dev_eui: Option<String>,
+}
+
+impl PortDetail {
+    fn new(info: &SerialPortInfo) -> PortDetail {
+        let mut detail = PortDetail {
+            port: info.port_name.clone(),
+            ..Default::default()
+        };
+        detail.port_type = match &info.port_type {
+            SerialPortType::UsbPort(usb) => {
+                detail.vid = Some(format!("{:04x}", usb.vid));
+                detail.pid = Some(format!("{:04x}", usb.pid));
+                detail.manufacturer = usb.manufacturer.clone();
+                detail.product = usb.product.clone();
+                detail.serial_number = usb.serial_number.clone();
+                "usb"
+            }
+            SerialPortType::PciPort => "pci",
+            SerialPortType::BluetoothPort => "bluetooth",
+            SerialPortType::Unknown => "unknown",
+        }
+        .to_string();
+        detail
+    }
+
+    /// Detect the module, then read its factory DevEUI
+    fn probe(&mut self) {
+        let found = match serial::probe::probe(&self.port, &MODULES, &BAUD_RATES) {
+            Some(found) => found,
+            None => return,
+        };
+        self.module = Some(found.module);
+        self.baud = Some(found.baud);
+        self.firmware = Some(found.version);
+        let modem = serial::open_modem(
+            &self.port,
+            Some(found.baud),
+            Some(found.module),
+            Duration::from_secs(2),
+        );
+        match modem.map(|mut modem| modem.hw_dev_eui()) {
+            Ok(Ok(dev_eui)) => self.dev_eui = dev_eui,
+            Ok(Err(e)) => debug!("Failed to read DevEUI of {}: {}", self.port, e),
+            Err(e) => debug!("Failed to open {}: {}", self.port, e),
+        }
+    }
+}
+
+/// Parse a USB ID in hex, with or without `0x`
+pub fn parse_usb_id(s: &str) -> Result<u16, String> {
+    let hex = s.trim_start_matches("0x").trim_start_matches("0X");
+    u16::from_str_radix(hex, 16).map_err(|e| format!("invalid USB ID {}: {}", s, e))
+}
+
+/// The serial ports whose USB VID and PID match. Without a filter every port matches.
+pub fn filter_ports(vid: Option<u16>, pid: Option<u16>) -> Vec<SerialPortInfo> {
+    let ports = serialport::available_ports().unwrap_or_else(|e| {
+        error!("Failed to list serial ports: {}", e);
+        Vec::new()
+    });
+    ports
+        .into_iter()
+        .filter(|port| match &port.port_type {
+            SerialPortType::UsbPort(usb) => {
+                vid.is_none_or(|vid| vid == usb.vid) && pid.is_none_or(|pid| pid == usb.pid)
+            }
+            _ => vid.is_none() && pid.is_none(),
+        })
+        .collect()
+}
+
+pub fn handle_ls(vid: Option<u16>, pid: Option<u16>, probe: bool, format: LsFormat) {
+    let mut details: Vec<PortDetail> = filter_ports(vid, pid).iter().map(PortDetail::new).collect();
+    if probe {
+        for detail in details.iter_mut() {
+            detail.probe();
+        }
+    }
+    match format {
+        LsFormat::Table => print_table(&details, probe),
+        LsFormat::Json => println!("{}", serde_json::to_string_pretty(&details).unwrap()),
+    }
+}
+
+fn print_table(details: &[PortDetail], probe: bool) {
+    let mut header = vec![
+        "PORT",
+        "TYPE",
+        "VID:PID",
+        "MANUFACTURER",
+        "PRODUCT",
+        "SERIAL",
+    ];
+    if probe {
+        header.extend(["MODULE", "BAUD", "FIRMWARE", "DEVEUI"]);
+    }
+    let rows: Vec<Vec<String>> = details
+        .iter()
+        .map(|d| {
+            let or_dash = |s: &Option<String>| s.clone().unwrap_or_else(|| "-".into());
+            let mut row = vec![
+                d.port.clone(),
+                d.port_type.clone(),
+                match (&d.vid, &d.pid) {
+                    (Some(vid), Some(pid)) => format!("{}:{}", vid, pid),
+                    _ => "-".into(),
+                },
+                or_dash(&d.manufacturer),
+                or_dash(&d.product),
+                or_dash(&d.serial_number),
+            ];
+            if probe {
+                row.push(or_dash(&d.module.map(|m| format!("{:?}", m))));
+                row.push(or_dash(&d.baud.map(|b| b.to_string())));
+                row.push(or_dash(&d.firmware));
+                row.push(or_dash(&d.dev_eui));
+            }
+            row
+        })
+        .collect();
+    let widths: Vec<usize> = (0..header.len())
+        .map(|i| {
+            rows.iter()
+                .map(|row| row[i].len())
+                .chain([header[i].len()])
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    let print_row = |cells: Vec<&str>| {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+    print_row(header.clone());
+    for row in &rows {
+        print_row(row.iter().map(|s| s.as_str()).collect());
+    }
+}
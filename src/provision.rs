@@ -0,0 +1,201 @@
+use crate::chirpstack::{self, AbpSession, LoraDevice};
+use crate::lora::JoinMode;
+use crate::serial::{self, LoraModem, ModuleKind, SerialError};
+use crate::user_config::Config;
+use crate::utils::gen_hex::{get_rand_app_key, verify_dev_eui};
+use log::{info, warn};
+use std::fmt;
+use std::thread;
+use std::time::Duration;
+
+/// Everything the provisioning flow needs besides the serial port
+#[derive(Debug, Clone)]
+pub struct ProvisionOptions {
+    pub baud: Option<u32>,
+    pub module: Option<ModuleKind>,
+    /// Generated randomly if empty
+    pub name: String,
+    pub description: String,
+    /// Generated randomly if empty
+    pub dev_eui: String,
+    /// Generated randomly if empty
+    pub app_key: String,
+    /// Register the DevEUI of the module instead of writing `dev_eui`
+    pub hw_dev_eui: bool,
+    /// OTAA writes the AppKey, ABP a random session which is then activated in ChirpStack
+    pub activation: JoinMode,
+}
+
+/// Which step of the provisioning flow failed
+#[derive(Debug)]
+pub enum ProvisionError {
+    Open(serialport::Error),
+    Serial(&'static str, SerialError),
+    InvalidDevEui(Option<String>),
+    Api(Box<ureq::Error>),
+    /// The worker of the port panicked, with the panic message
+    Panicked(String),
+}
+
+impl fmt::Display for ProvisionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProvisionError::Open(e) => write!(f, "failed to open serial port: {}", e),
+            ProvisionError::Serial(step, e) => write!(f, "failed to {}: {}", step, e),
+            ProvisionError::InvalidDevEui(dev_eui) => {
+                write!(f, "the module reported no valid DevEUI: {:?}", dev_eui)
+            }
+            ProvisionError::Api(e) => write!(f, "failed to register the device: {}", e),
+            ProvisionError::Panicked(msg) => write!(f, "the worker panicked: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ProvisionError {}
+
+/// Write the keys to the module on `path`, read them back, then register the
+/// device in ChirpStack. A module that fails any step is never registered.
+pub fn provision(
+    agent: &ureq::Agent,
+    cfg: &Config,
+    path: &str,
+    opts: &ProvisionOptions,
+) -> Result<LoraDevice, ProvisionError> {
+    let mut modem = serial::open_modem(path, opts.baud, opts.module, Duration::new(5, 0))
+        .map_err(ProvisionError::Open)?;
+    let dev_eui = if opts.hw_dev_eui {
+        match modem
+            .hw_dev_eui()
+            .map_err(|e| ProvisionError::Serial("read the DevEUI", e))?
+        {
+            Some(dev_eui) if verify_dev_eui(&dev_eui) => {
+                info!("Use the DevEUI of the module: {}", dev_eui);
+                dev_eui
+            }
+            dev_eui => return Err(ProvisionError::InvalidDevEui(dev_eui)),
+        }
+    } else {
+        opts.dev_eui.clone()
+    };
+    let app_key = match opts.activation {
+        JoinMode::Otaa => opts.app_key.clone(),
+        // never written nor registered, ABP has no AppKey
+        JoinMode::Abp => get_rand_app_key(),
+    };
+    let device = LoraDevice::new(cfg, &app_key, &dev_eui, &opts.description, &opts.name);
+    // the factory DevEUI too, the module joins with the DevEUI set, not its own
+    modem
+        .set_dev_eui(&device.dev_eui)
+        .map_err(|e| ProvisionError::Serial("set the DevEUI", e))?;
+    match opts.activation {
+        JoinMode::Otaa => {
+            modem
+                .set_app_key(&device.app_key)
+                .map_err(|e| ProvisionError::Serial("set the AppKey", e))?;
+            // never register a half-configured module
+            modem
+                .verify_keys(&device.dev_eui, &device.app_key)
+                .map_err(|e| ProvisionError::Serial("verify the keys", e))?;
+            info!("The device is configured successfully");
+            chirpstack::register_device(agent, cfg, &device)
+                .map_err(|e| ProvisionError::Api(Box::new(e)))?;
+        }
+        JoinMode::Abp => {
+            let session = AbpSession::random();
+            write_abp_session(modem.as_mut(), &device.dev_eui, &session)?;
+            info!("The device is configured successfully");
+            chirpstack::activate_device(agent, cfg, &device, &session)
+                .map_err(|e| ProvisionError::Api(Box::new(e)))?;
+        }
+    }
+    info!("The info has been updated successfully");
+    Ok(device)
+}
+
+/// Switch the module to ABP, write the session and read it back
+fn write_abp_session(
+    modem: &mut dyn LoraModem,
+    dev_eui: &str,
+    session: &AbpSession,
+) -> Result<(), ProvisionError> {
+    match modem.set_join_mode(JoinMode::Abp) {
+        // e.g. RN2483, joined with `at join --mode abp`
+        Err(SerialError::Unsupported(_)) => info!("The join mode is given when joining"),
+        result => result.map_err(|e| ProvisionError::Serial("set the join mode", e))?,
+    }
+    modem
+        .set_dev_addr(&session.dev_addr)
+        .map_err(|e| ProvisionError::Serial("set the DevAddr", e))?;
+    modem
+        .set_nwks_key(&session.nwks_key)
+        .map_err(|e| ProvisionError::Serial("set the NwkSKey", e))?;
+    modem
+        .set_apps_key(&session.apps_key)
+        .map_err(|e| ProvisionError::Serial("set the AppSKey", e))?;
+    modem
+        .verify_abp_keys(dev_eui, &session.dev_addr, &session.nwks_key, &session.apps_key)
+        .map_err(|e| ProvisionError::Serial("verify the keys", e))
+}
+
+/// Expand the glob patterns in `paths`, e.g. `/dev/ttyUSB*`. Other paths are kept as is.
+pub fn expand_paths(paths: &[String]) -> Vec<String> {
+    let mut expanded = Vec::new();
+    for path in paths {
+        if !path.contains(['*', '?', '[']) {
+            expanded.push(path.clone());
+            continue;
+        }
+        let matches: Vec<String> = match glob::glob(path) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.to_string_lossy().into_owned())
+                .collect(),
+            Err(e) => {
+                warn!("Invalid pattern {}: {}", path, e);
+                continue;
+            }
+        };
+        if matches.is_empty() {
+            warn!("No serial port matches {}", path);
+        }
+        expanded.extend(matches);
+    }
+    expanded.sort();
+    expanded.dedup();
+    expanded
+}
+
+/// Provision every port in `paths` at the same time, one thread per port.
+/// The results are in the same order as `paths`. A worker that panics only fails its port.
+pub fn provision_many(
+    cfg: &Config,
+    paths: &[String],
+    opts: &ProvisionOptions,
+) -> Vec<Result<LoraDevice, ProvisionError>> {
+    let agent = ureq::agent();
+    thread::scope(|s| {
+        let workers: Vec<_> = paths
+            .iter()
+            .map(|path| {
+                let agent = &agent;
+                thread::Builder::new()
+                    .name(path.clone())
+                    .spawn_scoped(s, move || provision(agent, cfg, path, opts))
+                    .expect("Failed to spawn worker")
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| {
+                worker.join().unwrap_or_else(|panic| {
+                    let msg = panic
+                        .downcast_ref::<&str>()
+                        .map(|msg| msg.to_string())
+                        .or_else(|| panic.downcast_ref::<String>().cloned())
+                        .unwrap_or_default();
+                    Err(ProvisionError::Panicked(msg))
+                })
+            })
+            .collect()
+    })
+}
@@ -1,68 +1,194 @@
 use crate::utils::escape_string;
-use log::debug;
-use log::info;
+use log::{debug, warn};
 use serialport::SerialPort;
+use std::fmt;
+use std::io;
 use std::io::BufRead;
 use std::io::BufReader;
+use std::io::Write;
+use std::mem;
+use std::time::{Duration, Instant};
 pub mod at;
+pub mod backup;
+pub mod modem;
+pub mod probe;
+pub mod rak811;
+pub mod response;
+pub mod rn2483;
+pub mod rui3;
+pub mod script;
+pub mod shell;
+pub mod transcript;
+pub mod transport;
+pub mod wio_e5;
 
-fn send_read(mut serial: Box<dyn SerialPort>, command: &str) -> String {
-    let w_buf = command;
-    let mut r_buf = String::new();
-    serial.write(w_buf.as_bytes()).expect("Failed to write");
-    debug!("Send Content: {}", escape_string(w_buf.to_string()));
-    // https://stackoverflow.com/questions/67520106/how-to-use-read-line-function-with-rusts-serialport-crate
-    let mut reader = BufReader::new(serial);
-    reader.read_line(&mut r_buf).expect("Failed to read");
-    debug!("Response: {}", escape_string(r_buf.clone()));
-    if r_buf.to_ascii_uppercase().contains("ERROR") {
-        panic!("Error from device: {}", r_buf);
-    }
-    return r_buf;
+pub use modem::{LoraModem, ModuleKind};
+pub use probe::open_modem;
+pub use response::{AtError, AtResponse, Downlink, LoraStatus, ModuleConfig};
+use transcript::{ReplayPort, Transcript};
+pub use transport::Transport;
+use transport::{Rfc2217Transport, TcpTransport};
+
+/// Anything that can go wrong while talking to the module
+#[derive(Debug)]
+pub enum SerialError {
+    /// The serial port itself failed
+    Io(io::Error),
+    /// The module answered with `ERROR: <code>`
+    Device(AtError),
+    /// No terminating `OK` or `ERROR` before the command timed out
+    Timeout,
+    /// The value read back from the module differs from what was written
+    Mismatch {
+        name: &'static str,
+        expected: String,
+        actual: Option<String>,
+    },
+    /// The module has no equivalent of the requested setting
+    Unsupported(&'static str),
 }
 
-pub fn at_version(serial: Box<dyn SerialPort>) {
-    send_read(serial, "at+version\r\n");
+impl fmt::Display for SerialError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SerialError::Io(e) => write!(f, "serial port error: {}", e),
+            SerialError::Device(e) => write!(f, "error from device: {}", e),
+            SerialError::Timeout => write!(f, "timeout waiting for response"),
+            SerialError::Mismatch {
+                name,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} mismatch: expected {}, read back {}",
+                name,
+                expected,
+                actual.as_deref().unwrap_or("nothing")
+            ),
+            SerialError::Unsupported(what) => write!(f, "{} is not supported by this module", what),
+        }
+    }
 }
 
-pub fn at_join(serial: Box<dyn SerialPort>) {
-    send_read(serial, "at+join\r\n");
+impl std::error::Error for SerialError {}
+
+impl From<io::Error> for SerialError {
+    fn from(e: io::Error) -> SerialError {
+        SerialError::Io(e)
+    }
 }
 
-/// This function won't check the DevEUI.
-/// Use with caution.
-/// at+set_config=lora:dev_eui:<dev_eui>\r\n
-pub fn at_dev_eui(serial: Box<dyn SerialPort>, dev_eui: &str) {
-    let w_buf = format!("at+set_config=lora:dev_eui:{}\r\n", dev_eui);
-    send_read(serial, &w_buf);
+impl From<AtError> for SerialError {
+    fn from(e: AtError) -> SerialError {
+        SerialError::Device(e)
+    }
 }
 
-/// This function won't check the AppKey.
-/// Use with caution.
-pub fn at_app_key(serial: Box<dyn SerialPort>, app_key: &str) {
-    let w_buf = format!("at+set_config=lora:app_key:{}\r\n", app_key);
-    send_read(serial, &w_buf);
+/// Open a serial port. Besides the local ports, `path` can be
+/// - `tcp://<host>:<port>` for the raw bytes over TCP, see `transport::TcpTransport`
+/// - `rfc2217://<host>:<port>` for telnet with COM port control, see `transport::Rfc2217Transport`
+/// - `replay://<file>` to play back a transcript, see `transcript::ReplayPort`
+pub fn open_port(
+    path: &str,
+    baud: u32,
+    timeout: Duration,
+) -> Result<Box<dyn Transport>, serialport::Error> {
+    if let Some(addr) = path.strip_prefix("tcp://") {
+        return Ok(Box::new(TcpTransport::connect(addr, timeout)?));
+    }
+    if let Some(addr) = path.strip_prefix("rfc2217://") {
+        return Ok(Box::new(Rfc2217Transport::connect(addr, baud, timeout)?));
+    }
+    let serial: Box<dyn SerialPort> = match path.strip_prefix("replay://") {
+        Some(file) => Box::new(ReplayPort::open(file, baud, timeout)?),
+        None => serialport::new(path, baud).timeout(timeout).open()?,
+    };
+    Ok(Box::new(serial))
 }
 
-pub fn at_send_msg<T>(serial: Box<dyn SerialPort>, chn: u8, msg: &T)
-where
-    T: AsRef<str>,
-{
-    // copilot did this
-    let hex_msg = msg
-        .as_ref()
-        .as_bytes()
-        .iter()
-        .map(|b| format!("{:02X}", b))
-        .collect::<Vec<String>>()
-        .join("")
-        .to_uppercase();
-    let w_buf = format!("at+send=lora:{}:{}\r\n", chn, hex_msg);
-    send_read(serial, &w_buf);
+/// A serial connection to a LoRa module that lives across several AT commands.
+/// It only deals with lines, the AT dialect is left to the drivers implementing `LoraModem`.
+///
+/// The port is opened once and the `BufReader` is kept around, so bytes that
+/// were buffered but not consumed by one command are not lost when the next
+/// command is sent.
+pub struct AtSession {
+    reader: BufReader<Box<dyn Transport>>,
+    /// A line that is not terminated yet when the last read timed out
+    line: String,
+    /// The default per-command timeout
+    timeout: Duration,
+    /// Where the traffic is recorded, if enabled by `transcript::record_to`
+    transcript: Option<Transcript>,
 }
 
-/// won't convert msg to hex
-pub fn at_send_raw(serial: Box<dyn SerialPort>, chn: u8, msg: &str) {
-    let w_buf = format!("at+send=lora:{}:{}\r\n", chn, msg);
-    send_read(serial, &w_buf);
+impl AtSession {
+    /// Open the serial port at `path` (see `open_port`) and wrap it into a session.
+    /// `timeout` is used as the default per-command timeout.
+    pub fn open(path: &str, baud: u32, timeout: Duration) -> Result<AtSession, serialport::Error> {
+        let serial = open_port(path, baud, timeout)?;
+        let mut session = AtSession::new(serial);
+        if let Some(dir) = transcript::record_dir() {
+            match Transcript::create(dir, path, baud) {
+                Ok(transcript) => session.transcript = Some(transcript),
+                Err(e) => warn!("Failed to create transcript in {:?}: {}", dir, e),
+            }
+        }
+        Ok(session)
+    }
+
+    /// Wrap an already opened port. The timeout of the port is used as
+    /// the default per-command timeout.
+    pub fn new(serial: Box<dyn Transport>) -> AtSession {
+        let timeout = serial.timeout();
+        AtSession {
+            reader: BufReader::new(serial),
+            line: String::new(),
+            timeout,
+            transcript: None,
+        }
+    }
+
+    /// The default per-command timeout
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Read a whole line, or `None` if nothing complete arrived before `deadline`.
+    /// A partial line is kept for the next call.
+    fn read_line(&mut self, deadline: Instant) -> Result<Option<String>, SerialError> {
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            self.reader.get_mut().set_timeout(deadline - now)?;
+            let start = self.line.len();
+            // https://stackoverflow.com/questions/67520106/how-to-use-read-line-function-with-rusts-serialport-crate
+            let result = self.reader.read_line(&mut self.line);
+            // a timed out read may still have appended a partial line
+            if let Some(transcript) = &mut self.transcript {
+                transcript.received(&self.line[start..]);
+            }
+            match result {
+                Ok(_) if self.line.ends_with('\n') => {
+                    let line = mem::take(&mut self.line);
+                    debug!("Response: {}", escape_string(line.clone()));
+                    return Ok(Some(line));
+                }
+                Ok(_) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn write_command(&mut self, command: &str) -> Result<(), SerialError> {
+        self.reader.get_mut().write_all(command.as_bytes())?;
+        if let Some(transcript) = &mut self.transcript {
+            transcript.sent(command);
+        }
+        debug!("Send Content: {}", escape_string(command.to_string()));
+        Ok(())
+    }
 }
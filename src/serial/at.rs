@@ -1,10 +1,15 @@
+use crate::lora::{Class, JoinMode, Region};
+use crate::payload::lpp::{self, Reading};
+use crate::payload::{self, Encoding};
 use crate::serial;
 use crate::utils;
-use crate::utils::gen_hex::is_hex;
+use crate::utils::gen_hex;
 use clap::arg;
-use clap::{Parser, Subcommand};
-use log::{debug, error, info, log_enabled, warn, Level};
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use log::{debug, info, log_enabled, warn, Level};
+use std::error::Error;
 use std::{io::Read, time::Duration};
+use ureq::serde_json;
 
 #[derive(Subcommand)]
 pub enum AtCommands {
@@ -16,14 +21,90 @@ pub enum AtCommands {
         command: SetCommands,
     },
     /// Send a `at+join` to make the device join the local Lora network
-    Join,
+    /// and wait until it is joined
+    Join {
+        /// How many times to try before giving up
+        #[arg(long, default_value_t = 3)]
+        attempts: u32,
+        /// Seconds to wait before the first retry, doubled after each failure
+        #[arg(long, default_value_t = 5)]
+        backoff: u64,
+        /// Seconds to wait for the join result of each attempt
+        #[arg(long, default_value_t = 30)]
+        timeout: u64,
+        /// Join by OTAA or ABP instead of the mode set on the module
+        #[arg(long, value_enum, ignore_case = true)]
+        mode: Option<JoinMode>,
+    },
+    /// Send a `at+get_config=lora:status` to show the DevEUI, AppEUI and AppKey of device
+    Status,
+    /// Wait for downlinks (`at+recv`) and print them until interrupted
+    Listen {
+        /// How to print the downlinks
+        #[arg(long, short, value_enum, default_value_t = ListenFormat::Text)]
+        format: ListenFormat,
+    },
+    /// Send an uplink. Unless the module can't report its region and data rate,
+    /// a payload too long for them is refused.
     Send {
-        msg: String,
+        /// The message. Read from `--file` if not set.
+        #[arg(
+            required_unless_present_any = ["file", "lpp"],
+            conflicts_with_all = ["file", "lpp"]
+        )]
+        msg: Option<String>,
         #[arg(long, short, default_value_t = 1)]
         chn: u8,
+        /// Send a confirmed uplink
         #[arg(long, action)]
+        confirm: bool,
+        /// Same as `--encoding hex`
+        #[arg(long, action, conflicts_with = "encoding")]
         raw: bool,
+        /// Read the message from a file, or from stdin if `-`
+        #[arg(long, short)]
+        file: Option<String>,
+        /// How the message becomes the payload
+        #[arg(long, short, value_enum, default_value_t = Encoding::Text)]
+        encoding: Encoding,
+        /// Send a Cayenne LPP reading instead of a message, as `<channel>:<type>:<value>`.
+        /// Repeat it for several readings, e.g. `--lpp 1:temperature:21.5 --lpp 2:humidity:40
+        /// --lpp 3:gps:52.3676,4.9041,10 --lpp 4:analog-input:3.3`
+        #[arg(long, value_parser = lpp::parse_reading, conflicts_with_all = ["file", "encoding", "raw"])]
+        lpp: Vec<Reading>,
+    },
+    /// Type AT commands interactively, with history and completion of the RAK811 commands
+    Shell,
+    /// Run a script of `send`, `expect <regex>` and `timeout <secs>` steps.
+    /// The named groups of the regexes are captured and can be used later as `${name}`.
+    Script {
+        /// The script file
+        file: String,
+        /// Set a variable before the script starts, e.g. `--var dev_eui=60C5A8FFFE000001`
+        #[arg(long, value_parser = parse_var)]
+        var: Vec<(String, String)>,
     },
+    /// Save the keys, region, class, DR, ADR and channel mask of the device.
+    /// The file is JSON if it ends with `.json`, TOML otherwise.
+    Backup { file: String },
+    /// Write the settings saved by `backup` to the device, e.g. a replacement module,
+    /// and read them back to verify
+    Restore { file: String },
+}
+
+fn parse_var(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected name=value, got {}", s))
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ListenFormat {
+    Text,
+    /// One JSON object per line
+    Json,
+    /// Like text, with the data decoded as Cayenne LPP
+    Lpp,
 }
 
 #[derive(Subcommand)]
@@ -38,53 +119,310 @@ pub enum SetCommands {
         #[clap(default_value = "")]
         app_key: String,
     },
+    /// The AppEUI (64 bit hex) identifies the join server, a.k.a. JoinEUI.
+    AppEui {
+        #[clap(value_parser = parse_app_eui)]
+        app_eui: String,
+    },
+    /// Join by OTAA or ABP
+    JoinMode {
+        #[arg(value_enum, ignore_case = true)]
+        mode: JoinMode,
+    },
+    /// The LoRaWAN region, e.g. EU868
+    Region {
+        #[arg(value_enum, ignore_case = true)]
+        region: Region,
+    },
+    /// The LoRaWAN class
+    Class {
+        #[arg(value_enum, ignore_case = true)]
+        class: Class,
+    },
+    /// Send confirmed (true) or unconfirmed (false) uplinks
+    Confirm {
+        #[arg(action = ArgAction::Set)]
+        confirm: bool,
+    },
+    /// The data rate. The valid range depends on the region.
+    Dr {
+        #[arg(value_parser = clap::value_parser!(u8).range(0..=15))]
+        dr: u8,
+    },
+    /// Enable (true) or disable (false) Adaptive Data Rate
+    Adr {
+        #[arg(action = ArgAction::Set)]
+        adr: bool,
+    },
+    /// The TX power level. The valid range depends on the region.
+    TxPower {
+        #[arg(value_parser = clap::value_parser!(u8).range(0..=10))]
+        tx_power: u8,
+    },
+    /// The DevAddr (32 bit hex) used by ABP.
+    DevAddr {
+        #[clap(default_value = "")]
+        dev_addr: String,
+    },
+    /// The Network Session Key (NwkSKey, 128 bit hex) used by ABP.
+    NwksKey {
+        #[clap(default_value = "")]
+        nwks_key: String,
+    },
+    /// The Application Session Key (AppSKey, 128 bit hex) used by ABP.
+    AppsKey {
+        #[clap(default_value = "")]
+        apps_key: String,
+    },
+    /// Enable (true) or disable (false) a channel
+    ChMask {
+        #[arg(value_parser = clap::value_parser!(u8).range(0..=71))]
+        channel: u8,
+        #[arg(action = ArgAction::Set)]
+        enabled: bool,
+    },
 }
 
-pub fn handle_at_commands(path: &String, baud: &u32, command: &AtCommands) {
-    let serial = serialport::new(path, *baud)
-        .timeout(Duration::new(15, 0)) // give a longer timeout for the command
-        .open()
-        .expect("Failed to open serial port");
-    match &command {
-        // https://stackoverflow.com/questions/63131868/how-to-pass-mut-str-and-change-the-original-mut-str-without-a-return
-        // https://stackoverflow.com/questions/68021274/pass-mut-reference-to-a-function-and-get-it-back
-        AtCommands::Version => {
-            serial::at_version(serial);
-        }
-        AtCommands::Join => {
-            serial::at_join(serial);
+fn parse_app_eui(s: &str) -> Result<String, String> {
+    if utils::gen_hex::verify_app_eui(s) {
+        Ok(s.to_string())
+    } else {
+        Err("AppEUI must be 64 bit hex".to_string())
+    }
+}
+
+/// Use `value` if it is valid, otherwise generate a random one
+fn valid_or_rand(value: &str, name: &str, verify: fn(&str) -> bool, gen: fn() -> String) -> String {
+    if verify(value) {
+        value.to_string()
+    } else {
+        warn!(
+            "Invalid {0} or {0} is not provided. Auto-generated {0} will be used.",
+            name
+        );
+        let value = gen();
+        info!("Please save the {}: {}", name, value);
+        value
+    }
+}
+
+/// Run the command. A module answering with an error is an error too, so
+/// that scripts can rely on the exit code.
+pub fn handle_at_commands(
+    path: &String,
+    baud: &Option<u32>,
+    module: &Option<serial::ModuleKind>,
+    command: &AtCommands,
+) -> Result<(), Box<dyn Error>> {
+    // the shell talks to the port directly, whatever the module is
+    match command {
+        AtCommands::Shell => {
+            serial::shell::handle_shell(path, *baud);
+            return Ok(());
         }
-        AtCommands::Set { command } => match command {
-            SetCommands::DevEui { dev_eui } => {
-                if utils::gen_hex::verify_dev_eui(dev_eui) {
-                    serial::at_dev_eui(serial, dev_eui);
-                } else {
-                    warn!("Invalid DevEUI or DevEUI is not provided. Auto-generated DevEUI will be used.");
-                    let dev_eui = utils::gen_hex::get_rand_dev_eui();
-                    info!("Please save the DevEUI: {}", dev_eui);
-                    serial::at_dev_eui(serial, &dev_eui);
-                }
-            }
-            SetCommands::AppKey { app_key } => {
-                if utils::gen_hex::verify_app_key(app_key) {
-                    serial::at_app_key(serial, app_key);
-                } else {
-                    warn!("Invalid AppKey or AppKey is not provided. Auto-generated AppKey will be used.");
-                    let app_key = utils::gen_hex::get_rand_app_key();
-                    info!("Please save the AppKey: {}", app_key);
-                    serial::at_app_key(serial, &app_key);
-                }
+        AtCommands::Script { file, var } => return handle_script(path, *baud, file, var),
+        _ => {}
+    }
+    // give a longer timeout for the command
+    let mut modem = serial::open_modem(path, *baud, *module, Duration::new(15, 0))
+        .map_err(|e| format!("failed to open serial port: {}", e))?;
+    let result = match &command {
+        AtCommands::Version => modem.version().map(|version| {
+            info!("Version: {}", version);
+        }),
+        AtCommands::Join {
+            attempts,
+            backoff,
+            timeout,
+            mode,
+        } => modem
+            .join_retry(
+                *mode,
+                *attempts,
+                Duration::from_secs(*backoff),
+                Duration::from_secs(*timeout),
+            )
+            .map(|report| {
+                info!(
+                    "Joined after {} attempt(s) in {:.1}s",
+                    report.attempts,
+                    report.elapsed.as_secs_f32()
+                );
+            }),
+        AtCommands::Listen { format } => modem.listen(&mut |downlink| match format {
+            ListenFormat::Text => println!(
+                "port: {}, rssi: {}, snr: {}, len: {}, data: {}",
+                downlink.port, downlink.rssi, downlink.snr, downlink.len, downlink.data
+            ),
+            ListenFormat::Json => println!("{}", serde_json::to_string(&downlink).unwrap()),
+            ListenFormat::Lpp => {
+                let readings = payload::decode_hex(&downlink.data)
+                    .ok_or_else(|| "the data is not hex".to_string())
+                    .and_then(|data| lpp::decode(&data).map_err(|e| e.to_string()));
+                let data = match readings {
+                    Ok(readings) => {
+                        let readings: Vec<String> =
+                            readings.iter().map(|r| r.to_string()).collect();
+                        readings.join(" ")
+                    }
+                    Err(e) => format!("{} ({})", downlink.data, e),
+                };
+                println!(
+                    "port: {}, rssi: {}, snr: {}, len: {}, lpp: {}",
+                    downlink.port, downlink.rssi, downlink.snr, downlink.len, data
+                )
             }
+        }),
+        AtCommands::Status => modem.status().map(|status| {
+            info!("{:#?}", status);
+        }),
+        AtCommands::Set { command } => match command {
+            SetCommands::DevEui { dev_eui } => modem.set_dev_eui(&valid_or_rand(
+                dev_eui,
+                "DevEUI",
+                gen_hex::verify_dev_eui,
+                gen_hex::get_rand_dev_eui,
+            )),
+            SetCommands::AppKey { app_key } => modem.set_app_key(&valid_or_rand(
+                app_key,
+                "AppKey",
+                gen_hex::verify_app_key,
+                gen_hex::get_rand_app_key,
+            )),
+            SetCommands::AppEui { app_eui } => modem.set_app_eui(app_eui),
+            SetCommands::JoinMode { mode } => modem.set_join_mode(*mode),
+            SetCommands::Region { region } => modem.set_region(*region),
+            SetCommands::Class { class } => modem.set_class(*class),
+            SetCommands::Confirm { confirm } => modem.set_confirm(*confirm),
+            SetCommands::Dr { dr } => modem.set_dr(*dr),
+            SetCommands::Adr { adr } => modem.set_adr(*adr),
+            SetCommands::TxPower { tx_power } => modem.set_tx_power(*tx_power),
+            SetCommands::DevAddr { dev_addr } => modem.set_dev_addr(&valid_or_rand(
+                dev_addr,
+                "DevAddr",
+                gen_hex::verify_dev_addr,
+                gen_hex::get_rand_dev_addr,
+            )),
+            SetCommands::NwksKey { nwks_key } => modem.set_nwks_key(&valid_or_rand(
+                nwks_key,
+                "NwkSKey",
+                gen_hex::verify_app_key,
+                gen_hex::get_rand_app_key,
+            )),
+            SetCommands::AppsKey { apps_key } => modem.set_apps_key(&valid_or_rand(
+                apps_key,
+                "AppSKey",
+                gen_hex::verify_app_key,
+                gen_hex::get_rand_app_key,
+            )),
+            SetCommands::ChMask { channel, enabled } => modem.set_ch_mask(*channel, *enabled),
         },
-        AtCommands::Send { msg, chn, raw } => {
-            if *raw {
-                if !is_hex(msg) {
-                    warn!("The message is not in hex format!");
-                }
-                serial::at_send_raw(serial, *chn, msg);
-            } else {
-                serial::at_send_msg(serial, *chn, msg);
-            }
+        AtCommands::Send {
+            msg,
+            chn,
+            confirm,
+            raw,
+            file,
+            encoding,
+            lpp,
+        } => {
+            let encoding = if *raw { Encoding::Hex } else { *encoding };
+            let payload = build_payload(
+                modem.as_mut(),
+                msg.as_deref(),
+                file.as_deref(),
+                encoding,
+                lpp,
+            )?;
+            modem.send_bytes(*chn, &payload, *confirm)
         }
+        AtCommands::Backup { file } => return handle_backup(modem.as_mut(), file),
+        AtCommands::Restore { file } => return handle_restore(modem.as_mut(), file),
+        AtCommands::Shell | AtCommands::Script { .. } => unreachable!(),
+    };
+    Ok(result?)
+}
+
+fn handle_script(
+    path: &str,
+    baud: Option<u32>,
+    file: &str,
+    var: &[(String, String)],
+) -> Result<(), Box<dyn Error>> {
+    let baud = serial::probe::detect_baud(path, baud);
+    let mut session = serial::AtSession::open(path, baud, Duration::new(5, 0))
+        .map_err(|e| format!("failed to open serial port: {}", e))?;
+    let vars = serial::script::run_file(&mut session, file, var.iter().cloned().collect())
+        .map_err(|e| format!("FAIL {}: {}", file, e))?;
+    info!("PASS {}", file);
+    let mut vars: Vec<_> = vars.into_iter().collect();
+    vars.sort();
+    for (name, value) in vars {
+        println!("{}={}", name, value);
     }
+    Ok(())
+}
+
+fn handle_backup(modem: &mut dyn serial::LoraModem, file: &str) -> Result<(), Box<dyn Error>> {
+    let backup =
+        serial::backup::backup(modem).map_err(|e| format!("failed to read the settings: {}", e))?;
+    serial::backup::save(&backup, file).map_err(|e| format!("failed to write {}: {}", file, e))?;
+    info!("Saved the settings to {}", file);
+    Ok(())
+}
+
+fn handle_restore(modem: &mut dyn serial::LoraModem, file: &str) -> Result<(), Box<dyn Error>> {
+    let backup =
+        serial::backup::load(file).map_err(|e| format!("failed to read {}: {}", file, e))?;
+    if let Some(firmware) = &backup.firmware {
+        info!("Backup taken from firmware {}", firmware);
+    }
+    serial::backup::restore(modem, &backup.config)
+        .and_then(|_| serial::backup::verify(modem, &backup.config))
+        .map_err(|e| format!("failed to restore {}: {}", file, e))?;
+    info!("Restored and verified the settings from {}", file);
+    Ok(())
+}
+
+/// Read the message and encode it, or encode the LPP readings if any.
+/// Then check the length against the region and data rate.
+fn build_payload(
+    modem: &mut dyn serial::LoraModem,
+    msg: Option<&str>,
+    file: Option<&str>,
+    encoding: Encoding,
+    lpp: &[Reading],
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let payload = if lpp.is_empty() {
+        encode_message(msg, file, encoding)?
+    } else {
+        lpp::encode(lpp)?
+    };
+    match modem.config() {
+        Ok(serial::ModuleConfig {
+            region: Some(region),
+            dr: Some(dr),
+            ..
+        }) => payload::check_length(payload.len(), region, dr)?,
+        _ => warn!("Can't read the region and data rate, the payload length is not checked"),
+    }
+    Ok(payload)
+}
+
+fn encode_message(
+    msg: Option<&str>,
+    file: Option<&str>,
+    encoding: Encoding,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let input = match (msg, file) {
+        (Some(msg), _) => msg.as_bytes().to_vec(),
+        (None, Some("-")) => {
+            let mut input = Vec::new();
+            std::io::stdin().read_to_end(&mut input)?;
+            input
+        }
+        (None, Some(file)) => std::fs::read(file)?,
+        (None, None) => Vec::new(),
+    };
+    Ok(payload::encode(&input, encoding)?)
 }
@@ -0,0 +1,135 @@
+use super::{LoraModem, ModuleConfig, SerialError};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/// The file written by `at backup`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Backup {
+    /// The firmware the backup was taken from, for reference only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub firmware: Option<String>,
+    pub config: ModuleConfig,
+}
+
+fn is_json(file: &str) -> bool {
+    Path::new(file)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("json"))
+}
+
+/// Keys are compared ignoring the case of the hex digits
+fn same_key(current: &Option<String>, key: &str) -> bool {
+    current
+        .as_ref()
+        .is_some_and(|v| v.eq_ignore_ascii_case(key))
+}
+
+/// Read the settings of the module
+pub fn backup(modem: &mut dyn LoraModem) -> Result<Backup, SerialError> {
+    let firmware = modem.version().ok();
+    let config = modem.config()?;
+    Ok(Backup { firmware, config })
+}
+
+/// Write JSON if `file` ends with `.json`, TOML otherwise
+pub fn save(backup: &Backup, file: &str) -> Result<(), Box<dyn Error>> {
+    let content = if is_json(file) {
+        ureq::serde_json::to_string_pretty(backup)?
+    } else {
+        toml::to_string_pretty(backup)?
+    };
+    fs::write(file, content)?;
+    Ok(())
+}
+
+/// Read a file written by `save`
+pub fn load(file: &str) -> Result<Backup, Box<dyn Error>> {
+    let content = fs::read_to_string(file)?;
+    if is_json(file) {
+        Ok(ureq::serde_json::from_str(&content)?)
+    } else {
+        Ok(toml::from_str(&content)?)
+    }
+}
+
+/// Write the settings in `config` that differ from the module's.
+///
+/// The region goes first as changing it resets the channels, the keys go last.
+pub fn restore(modem: &mut dyn LoraModem, config: &ModuleConfig) -> Result<(), SerialError> {
+    let mut current = modem.config()?;
+    if let Some(region) = config.region {
+        if current.region != Some(region) {
+            info!("Restore region {}", region.as_str());
+            modem.set_region(region)?;
+            current = modem.config()?;
+        }
+    }
+    if let Some(class) = config.class {
+        if current.class != Some(class) {
+            info!("Restore class {:?}", class);
+            modem.set_class(class)?;
+        }
+    }
+    if let Some(dr) = config.dr {
+        if current.dr != Some(dr) {
+            info!("Restore DR {}", dr);
+            modem.set_dr(dr)?;
+        }
+    }
+    if let Some(adr) = config.adr {
+        if current.adr != Some(adr) {
+            info!("Restore ADR {}", adr);
+            modem.set_adr(adr)?;
+        }
+    }
+    if let Some(channels) = &config.ch_mask {
+        let enabled = current.ch_mask.clone().unwrap_or_default();
+        for channel in enabled.iter().filter(|c| !channels.contains(c)) {
+            info!("Disable channel {}", channel);
+            modem.set_ch_mask(*channel, false)?;
+        }
+        for channel in channels.iter().filter(|c| !enabled.contains(c)) {
+            info!("Enable channel {}", channel);
+            modem.set_ch_mask(*channel, true)?;
+        }
+    }
+    if let Some(dev_eui) = &config.dev_eui {
+        if !same_key(&current.dev_eui, dev_eui) {
+            info!("Restore DevEUI {}", dev_eui);
+            modem.set_dev_eui(dev_eui)?;
+        }
+    }
+    if let Some(app_eui) = &config.app_eui {
+        if !same_key(&current.app_eui, app_eui) {
+            info!("Restore AppEUI {}", app_eui);
+            modem.set_app_eui(app_eui)?;
+        }
+    }
+    if let Some(app_key) = &config.app_key {
+        if !same_key(&current.app_key, app_key) {
+            info!("Restore AppKey");
+            modem.set_app_key(app_key)?;
+        }
+    }
+    Ok(())
+}
+
+/// Read the settings back and compare them with every field set in `config`
+pub fn verify(modem: &mut dyn LoraModem, config: &ModuleConfig) -> Result<(), SerialError> {
+    let current = modem.config()?;
+    for ((name, expected), (_, actual)) in config.fields().into_iter().zip(current.fields()) {
+        if let Some(expected) = expected {
+            if actual.as_ref() != Some(&expected) {
+                return Err(SerialError::Mismatch {
+                    name,
+                    expected,
+                    actual,
+                });
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,290 @@
+use super::rak811::Rak811;
+use super::rn2483::Rn2483;
+use super::rui3::Rui3;
+use super::wio_e5::WioE5;
+use super::{AtSession, Downlink, LoraStatus, ModuleConfig, SerialError};
+use crate::lora::{Class, JoinMode, Region};
+use crate::utils::escape_string;
+use clap::ValueEnum;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long `listen_lines` blocks on a single read before checking again
+const LISTEN_POLL: Duration = Duration::from_secs(1);
+
+/// The LoRa module families we know how to talk to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ModuleKind {
+    /// RAK811 with the V3 AT firmware
+    Rak811,
+    /// RAK3172 and other modules with the RUI3 firmware
+    Rui3,
+    /// Seeed LoRa-E5 / Wio-E5
+    WioE5,
+    /// Microchip RN2483 / RN2903, 57600 baud by default
+    Rn2483,
+}
+
+impl ModuleKind {
+    /// Wrap the session into the driver of this module
+    pub fn connect(self, session: AtSession) -> Box<dyn LoraModem> {
+        match self {
+            ModuleKind::Rak811 => Box::new(Rak811::new(session)),
+            ModuleKind::Rui3 => Box::new(Rui3::new(session)),
+            ModuleKind::WioE5 => Box::new(WioE5::new(session)),
+            ModuleKind::Rn2483 => Box::new(Rn2483::new(session)),
+        }
+    }
+}
+
+/// The outcome of a successful `join_retry`
+#[derive(Debug, Clone, Copy)]
+pub struct JoinReport {
+    /// Which attempt succeeded, starting from 1
+    pub attempts: u32,
+    /// From the first join request to the join result, including the backoff
+    pub elapsed: Duration,
+}
+
+/// What the tool needs from a LoRa module, whatever AT dialect it speaks.
+///
+/// The configuration setters below `listen` are optional. A driver that
+/// doesn't override them reports `SerialError::Unsupported`.
+pub trait LoraModem {
+    /// The firmware version
+    fn version(&mut self) -> Result<String, SerialError>;
+
+    fn set_dev_eui(&mut self, dev_eui: &str) -> Result<(), SerialError>;
+
+    fn set_app_eui(&mut self, app_eui: &str) -> Result<(), SerialError>;
+
+    fn set_app_key(&mut self, app_key: &str) -> Result<(), SerialError>;
+
+    /// Read the keys back from the module
+    fn status(&mut self) -> Result<LoraStatus, SerialError>;
+
+    /// The DevEUI programmed into the module by the factory. Not the DevEUI of
+    /// `status`, which is gone once overwritten.
+    fn hw_dev_eui(&mut self) -> Result<Option<String>, SerialError> {
+        Err(SerialError::Unsupported("reading the factory DevEUI"))
+    }
+
+    /// Read the settings kept by `at backup`
+    fn config(&mut self) -> Result<ModuleConfig, SerialError> {
+        Err(SerialError::Unsupported("reading the config"))
+    }
+
+    /// Join the network and wait for the result
+    fn join(&mut self, timeout: Duration) -> Result<(), SerialError>;
+
+    /// Send an uplink. `payload` is hex.
+    fn send(&mut self, port: u8, payload: &str) -> Result<(), SerialError>;
+
+    /// Join with `mode`. The mode is stored first, modules that take it with
+    /// every join override this.
+    fn join_as(&mut self, mode: JoinMode, timeout: Duration) -> Result<(), SerialError> {
+        self.set_join_mode(mode)?;
+        self.join(timeout)
+    }
+
+    /// Send a confirmed uplink. Confirm is stored first, so later uplinks are
+    /// confirmed too, modules that pick it per uplink override this.
+    fn send_confirmed(&mut self, port: u8, payload: &str) -> Result<(), SerialError> {
+        self.set_confirm(true)?;
+        self.send(port, payload)
+    }
+
+    /// Wait for downlinks forever. Only returns on serial port errors.
+    fn listen(&mut self, on_downlink: &mut dyn FnMut(Downlink)) -> Result<(), SerialError>;
+
+    fn set_join_mode(&mut self, _mode: JoinMode) -> Result<(), SerialError> {
+        Err(SerialError::Unsupported("join mode"))
+    }
+
+    fn set_region(&mut self, _region: Region) -> Result<(), SerialError> {
+        Err(SerialError::Unsupported("region"))
+    }
+
+    fn set_class(&mut self, _class: Class) -> Result<(), SerialError> {
+        Err(SerialError::Unsupported("class"))
+    }
+
+    /// Send confirmed (`true`) or unconfirmed (`false`) uplinks
+    fn set_confirm(&mut self, _confirm: bool) -> Result<(), SerialError> {
+        Err(SerialError::Unsupported("confirm"))
+    }
+
+    /// The valid range depends on the region
+    fn set_dr(&mut self, _dr: u8) -> Result<(), SerialError> {
+        Err(SerialError::Unsupported("data rate"))
+    }
+
+    fn set_adr(&mut self, _adr: bool) -> Result<(), SerialError> {
+        Err(SerialError::Unsupported("ADR"))
+    }
+
+    /// The valid range depends on the region
+    fn set_tx_power(&mut self, _tx_power: u8) -> Result<(), SerialError> {
+        Err(SerialError::Unsupported("TX power"))
+    }
+
+    /// This function won't check the DevAddr.
+    /// Use with caution.
+    fn set_dev_addr(&mut self, _dev_addr: &str) -> Result<(), SerialError> {
+        Err(SerialError::Unsupported("DevAddr"))
+    }
+
+    /// This function won't check the NwkSKey.
+    /// Use with caution.
+    fn set_nwks_key(&mut self, _nwks_key: &str) -> Result<(), SerialError> {
+        Err(SerialError::Unsupported("NwkSKey"))
+    }
+
+    /// This function won't check the AppSKey.
+    /// Use with caution.
+    fn set_apps_key(&mut self, _apps_key: &str) -> Result<(), SerialError> {
+        Err(SerialError::Unsupported("AppSKey"))
+    }
+
+    /// Enable or disable a single channel
+    fn set_ch_mask(&mut self, _channel: u8, _enabled: bool) -> Result<(), SerialError> {
+        Err(SerialError::Unsupported("channel mask"))
+    }
+
+    /// Send `payload` as is, hex-encoded for the AT command
+    fn send_bytes(&mut self, port: u8, payload: &[u8], confirm: bool) -> Result<(), SerialError> {
+        // copilot did this
+        let hex_msg = payload
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<String>>()
+            .join("")
+            .to_uppercase();
+        if confirm {
+            self.send_confirmed(port, &hex_msg)
+        } else {
+            self.send(port, &hex_msg)
+        }
+    }
+
+    /// Try `join` up to `attempts` times. The wait between attempts starts at
+    /// `backoff` and doubles after each failure. Serial port errors are not retried.
+    /// `mode` is the join mode to use, the module's own if `None`.
+    fn join_retry(
+        &mut self,
+        mode: Option<JoinMode>,
+        attempts: u32,
+        backoff: Duration,
+        timeout: Duration,
+    ) -> Result<JoinReport, SerialError> {
+        let start = Instant::now();
+        let mut delay = backoff;
+        let mut attempt = 1;
+        loop {
+            let result = match mode {
+                Some(mode) => self.join_as(mode, timeout),
+                None => self.join(timeout),
+            };
+            match result {
+                Ok(()) => {
+                    return Ok(JoinReport {
+                        attempts: attempt,
+                        elapsed: start.elapsed(),
+                    })
+                }
+                Err(SerialError::Io(e)) => return Err(SerialError::Io(e)),
+                Err(e) if attempt >= attempts => return Err(e),
+                Err(e) => {
+                    warn!(
+                        "Join attempt {}/{} failed: {}. Retry in {:?}",
+                        attempt, attempts, e, delay
+                    );
+                    thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// Read the status back and make sure the module holds the given DevEUI and AppKey
+    fn verify_keys(&mut self, dev_eui: &str, app_key: &str) -> Result<(), SerialError> {
+        let status = self.status()?;
+        debug!("Read back: {:#?}", status);
+        check_field("DevEUI", dev_eui, status.dev_eui)?;
+        check_field("AppKey", app_key, status.app_key)?;
+        Ok(())
+    }
+
+    /// Read the status back and make sure the module holds the given DevEUI and ABP session
+    fn verify_abp_keys(
+        &mut self,
+        dev_eui: &str,
+        dev_addr: &str,
+        nwks_key: &str,
+        apps_key: &str,
+    ) -> Result<(), SerialError> {
+        let status = self.status()?;
+        debug!("Read back: {:#?}", status);
+        check_field("DevEUI", dev_eui, status.dev_eui)?;
+        check_field("DevAddr", dev_addr, status.dev_addr)?;
+        check_field("NwkSKey", nwks_key, status.nwks_key)?;
+        check_field("AppSKey", apps_key, status.apps_key)?;
+        Ok(())
+    }
+}
+
+pub(super) fn check_field(
+    name: &'static str,
+    expected: &str,
+    actual: Option<String>,
+) -> Result<(), SerialError> {
+    match actual {
+        Some(ref actual) if actual.eq_ignore_ascii_case(expected) => Ok(()),
+        _ => Err(SerialError::Mismatch {
+            name,
+            expected: expected.to_string(),
+            actual,
+        }),
+    }
+}
+
+/// The loop behind `listen`: read lines forever and hand the downlinks found
+/// by `parse` to `on_downlink`. Only returns on serial port errors.
+pub(super) fn listen_lines(
+    session: &mut AtSession,
+    parse: &mut dyn FnMut(&str) -> Option<Downlink>,
+    on_downlink: &mut dyn FnMut(Downlink),
+) -> Result<(), SerialError> {
+    loop {
+        let line = match session.read_line(Instant::now() + LISTEN_POLL)? {
+            Some(line) => line,
+            None => continue,
+        };
+        match parse(&line) {
+            Some(downlink) => on_downlink(downlink),
+            None => debug!("Ignored: {}", escape_string(line)),
+        }
+    }
+}
+
+/// `1` or `0`, the booleans of RAK811 and RUI3
+pub(super) fn bool_value(value: bool) -> &'static str {
+    if value {
+        "1"
+    } else {
+        "0"
+    }
+}
+
+/// `on` or `off`, the booleans of RN2483 and, in upper case, Wio-E5
+pub(super) fn on_off(value: bool) -> &'static str {
+    if value {
+        "on"
+    } else {
+        "off"
+    }
+}
@@ -0,0 +1,102 @@
+use super::{open_port, AtSession, LoraModem, ModuleKind};
+use log::{debug, info, warn};
+use std::time::Duration;
+
+/// The baud rates tried when none is given, the most common first
+pub const BAUD_RATES: [u32; 5] = [115200, 57600, 9600, 38400, 19200];
+/// Every module family, in the order they are tried
+pub const MODULES: [ModuleKind; 4] = [
+    ModuleKind::Rak811,
+    ModuleKind::Rui3,
+    ModuleKind::WioE5,
+    ModuleKind::Rn2483,
+];
+/// How long to wait for the answer of each identification command
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What `probe` found on a port
+#[derive(Debug, Clone)]
+pub struct ProbeResult {
+    pub module: ModuleKind,
+    pub baud: u32,
+    /// The firmware version reported by the module
+    pub version: String,
+}
+
+/// Ask for the firmware version (`at+version`, `AT+VER=?`, `AT+VER`,
+/// `sys get ver`) with every module driver at every baud rate, and return
+/// the first one that gets a sensible answer.
+pub fn probe(path: &str, modules: &[ModuleKind], bauds: &[u32]) -> Option<ProbeResult> {
+    for baud in bauds {
+        for module in modules {
+            match probe_one(path, *module, *baud) {
+                Ok(Some(version)) => {
+                    return Some(ProbeResult {
+                        module: *module,
+                        baud: *baud,
+                        version,
+                    })
+                }
+                Ok(None) => {}
+                Err(e) => debug!("Probe {:?} at {} baud: {}", module, baud, e),
+            }
+        }
+    }
+    None
+}
+
+fn probe_one(
+    path: &str,
+    module: ModuleKind,
+    baud: u32,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let mut serial = open_port(path, baud, PROBE_TIMEOUT)?;
+    // drop whatever the previous attempt left behind
+    serial.clear()?;
+    let mut modem = module.connect(AtSession::new(serial));
+    let version = modem.version()?;
+    let plausible = match module {
+        // RN2483 answers anything with a single line, even `invalid_param`
+        ModuleKind::Rn2483 => version.starts_with("RN2"),
+        _ => !version.is_empty(),
+    };
+    Ok(if plausible { Some(version) } else { None })
+}
+
+/// `baud` if given, otherwise the baud rate any module answers at, or 115200
+pub fn detect_baud(path: &str, baud: Option<u32>) -> u32 {
+    baud.or_else(|| probe(path, &MODULES, &BAUD_RATES).map(|found| found.baud))
+        .unwrap_or(115200)
+}
+
+/// Open the port and connect the module driver. A missing module or baud rate
+/// is detected by `probe`; if nothing answers, RAK811 at 115200 baud is assumed.
+pub fn open_modem(
+    path: &str,
+    baud: Option<u32>,
+    module: Option<ModuleKind>,
+    timeout: Duration,
+) -> Result<Box<dyn LoraModem>, serialport::Error> {
+    let (module, baud) = match (module, baud) {
+        (Some(module), Some(baud)) => (module, baud),
+        (module, baud) => {
+            let modules = module.map(|m| vec![m]).unwrap_or_else(|| MODULES.to_vec());
+            let bauds = baud.map(|b| vec![b]).unwrap_or_else(|| BAUD_RATES.to_vec());
+            match probe(path, &modules, &bauds) {
+                Some(found) => {
+                    info!(
+                        "Found {:?} at {} baud, firmware {}",
+                        found.module, found.baud, found.version
+                    );
+                    (found.module, found.baud)
+                }
+                None => {
+                    warn!("No module answered the probe. RAK811 will be assumed.");
+                    (module.unwrap_or(ModuleKind::Rak811), baud.unwrap_or(115200))
+                }
+            }
+        }
+    };
+    let session = AtSession::open(path, baud, timeout)?;
+    Ok(module.connect(session))
+}
@@ -0,0 +1,214 @@
+use super::modem::{bool_value, listen_lines};
+use super::response::parse_channels;
+use super::{AtResponse, AtSession, Downlink, LoraModem, LoraStatus, ModuleConfig, SerialError};
+use crate::lora::{Class, JoinMode, Region};
+use log::info;
+use std::time::{Duration, Instant};
+
+/// How long the line must be quiet after a terminator before the response is
+/// considered complete. RAK811 prints `at+get_config=lora:status` as
+/// `OK <first line>` followed by the rest, without another terminator.
+const TRAILING_GAP: Duration = Duration::from_millis(200);
+
+/// RAK811 with the V3 AT firmware.
+/// See "RAK811 Module AT Command Manual" in `docs`.
+pub struct Rak811 {
+    session: AtSession,
+}
+
+impl Rak811 {
+    pub fn new(session: AtSession) -> Rak811 {
+        Rak811 { session }
+    }
+
+    /// Collect the lines following a terminator until the line goes quiet
+    fn read_trailing(&mut self, body: &mut Vec<String>) -> Result<(), SerialError> {
+        while let Some(line) = self.session.read_line(Instant::now() + TRAILING_GAP)? {
+            match AtResponse::parse(&line) {
+                AtResponse::Event(event) => info!("Event: {}", event),
+                _ => body.push(line.trim().to_string()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Send the command and collect the response until `OK` or `ERROR`.
+    /// The returned body is the payload of `OK` plus every other line, joined by `\n`.
+    /// Async events received in the meantime are logged and skipped.
+    fn send_read(&mut self, command: &str) -> Result<String, SerialError> {
+        let timeout = self.session.timeout();
+        self.send_read_timeout(command, timeout)
+    }
+
+    fn send_read_timeout(
+        &mut self,
+        command: &str,
+        timeout: Duration,
+    ) -> Result<String, SerialError> {
+        self.session.write_command(command)?;
+        let deadline = Instant::now() + timeout;
+        let mut body: Vec<String> = Vec::new();
+        loop {
+            let line = match self.session.read_line(deadline)? {
+                Some(line) => line,
+                None => return Err(SerialError::Timeout),
+            };
+            match AtResponse::parse(&line) {
+                AtResponse::Ok(payload) => {
+                    if !payload.is_empty() {
+                        body.push(payload);
+                    }
+                    self.read_trailing(&mut body)?;
+                    return Ok(body.join("\n"));
+                }
+                AtResponse::Error(e) => {
+                    self.read_trailing(&mut body)?;
+                    return Err(SerialError::Device(e));
+                }
+                AtResponse::Event(event) => info!("Event: {}", event),
+                AtResponse::Info(info) => {
+                    if !info.is_empty() {
+                        body.push(info);
+                    }
+                }
+            }
+        }
+    }
+
+    /// at+set_config=lora:<key>:<value>\r\n
+    fn set_config(&mut self, key: &str, value: &str) -> Result<(), SerialError> {
+        let w_buf = format!("at+set_config=lora:{}:{}\r\n", key, value);
+        self.send_read(&w_buf)?;
+        Ok(())
+    }
+}
+
+impl LoraModem for Rak811 {
+    fn version(&mut self) -> Result<String, SerialError> {
+        self.send_read("at+version\r\n")
+    }
+
+    /// This function won't check the DevEUI.
+    /// Use with caution.
+    /// at+set_config=lora:dev_eui:<dev_eui>\r\n
+    fn set_dev_eui(&mut self, dev_eui: &str) -> Result<(), SerialError> {
+        self.set_config("dev_eui", dev_eui)
+    }
+
+    /// This function won't check the AppEUI.
+    /// Use with caution.
+    fn set_app_eui(&mut self, app_eui: &str) -> Result<(), SerialError> {
+        self.set_config("app_eui", app_eui)
+    }
+
+    /// This function won't check the AppKey.
+    /// Use with caution.
+    fn set_app_key(&mut self, app_key: &str) -> Result<(), SerialError> {
+        self.set_config("app_key", app_key)
+    }
+
+    /// at+get_config=lora:status\r\n
+    fn status(&mut self) -> Result<LoraStatus, SerialError> {
+        let body = self.send_read("at+get_config=lora:status\r\n")?;
+        Ok(LoraStatus::parse(&body))
+    }
+
+    /// at+get_config=lora:status and at+get_config=lora:channel
+    fn config(&mut self) -> Result<ModuleConfig, SerialError> {
+        let status = self.send_read("at+get_config=lora:status\r\n")?;
+        let channels = self.send_read("at+get_config=lora:channel\r\n")?;
+        let mut config = ModuleConfig::parse(&status);
+        config.ch_mask = Some(parse_channels(&channels));
+        Ok(config)
+    }
+
+    /// Send `at+join` and wait for `OK Join Success`.
+    /// A bare `OK` is only the acknowledgement of the command, the result comes later.
+    fn join(&mut self, timeout: Duration) -> Result<(), SerialError> {
+        self.session.write_command("at+join\r\n")?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            let line = match self.session.read_line(deadline)? {
+                Some(line) => line,
+                None => return Err(SerialError::Timeout),
+            };
+            match AtResponse::parse(&line) {
+                AtResponse::Ok(payload) | AtResponse::Info(payload)
+                    if payload.to_ascii_lowercase().contains("join success") =>
+                {
+                    return Ok(());
+                }
+                AtResponse::Error(e) => return Err(SerialError::Device(e)),
+                AtResponse::Event(event) => info!("Event: {}", event),
+                _ => {}
+            }
+        }
+    }
+
+    /// won't convert payload to hex
+    fn send(&mut self, port: u8, payload: &str) -> Result<(), SerialError> {
+        let w_buf = format!("at+send=lora:{}:{}\r\n", port, payload);
+        self.send_read(&w_buf)?;
+        Ok(())
+    }
+
+    /// Wait for `at+recv` lines forever and hand every downlink to `on_downlink`.
+    /// Only returns on serial port errors.
+    fn listen(&mut self, on_downlink: &mut dyn FnMut(Downlink)) -> Result<(), SerialError> {
+        listen_lines(&mut self.session, &mut Downlink::parse, on_downlink)
+    }
+
+    fn set_join_mode(&mut self, mode: JoinMode) -> Result<(), SerialError> {
+        let value = match mode {
+            JoinMode::Otaa => "0",
+            JoinMode::Abp => "1",
+        };
+        self.set_config("join_mode", value)
+    }
+
+    fn set_region(&mut self, region: Region) -> Result<(), SerialError> {
+        self.set_config("region", region.as_str())
+    }
+
+    fn set_class(&mut self, class: Class) -> Result<(), SerialError> {
+        let value = match class {
+            Class::A => "0",
+            Class::B => "1",
+            Class::C => "2",
+        };
+        self.set_config("class", value)
+    }
+
+    fn set_confirm(&mut self, confirm: bool) -> Result<(), SerialError> {
+        self.set_config("confirm", bool_value(confirm))
+    }
+
+    fn set_dr(&mut self, dr: u8) -> Result<(), SerialError> {
+        self.set_config("dr", &dr.to_string())
+    }
+
+    fn set_adr(&mut self, adr: bool) -> Result<(), SerialError> {
+        self.set_config("adr", bool_value(adr))
+    }
+
+    fn set_tx_power(&mut self, tx_power: u8) -> Result<(), SerialError> {
+        self.set_config("tx_power", &tx_power.to_string())
+    }
+
+    fn set_dev_addr(&mut self, dev_addr: &str) -> Result<(), SerialError> {
+        self.set_config("dev_addr", dev_addr)
+    }
+
+    fn set_nwks_key(&mut self, nwks_key: &str) -> Result<(), SerialError> {
+        self.set_config("nwks_key", nwks_key)
+    }
+
+    fn set_apps_key(&mut self, apps_key: &str) -> Result<(), SerialError> {
+        self.set_config("apps_key", apps_key)
+    }
+
+    /// at+set_config=lora:ch_mask:<channel>:<0|1>\r\n
+    fn set_ch_mask(&mut self, channel: u8, enabled: bool) -> Result<(), SerialError> {
+        self.set_config("ch_mask", &format!("{}:{}", channel, bool_value(enabled)))
+    }
+}
@@ -0,0 +1,558 @@
+use crate::lora::{Class, Region};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// The error codes reported by RAK811 as `ERROR: <code>`.
+///
+/// See "RAK811 Module AT Command Manual" in `docs` for the full list.
+/// Drivers of other modules map their errors onto the closest code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtError {
+    /// 1
+    UnsupportedCommand,
+    /// 2
+    InvalidParameter,
+    /// 3
+    FlashAccess,
+    /// 5
+    UartSend,
+    /// 41
+    BleInvalidState,
+    /// 80
+    Busy,
+    /// 81
+    UnknownService,
+    /// 82
+    InvalidLoraParameter,
+    /// 83
+    InvalidFrequency,
+    /// 84
+    InvalidDataRate,
+    /// 85
+    InvalidFrequencyAndDataRate,
+    /// 86
+    NotJoined,
+    /// 87
+    PacketTooLong,
+    /// 88
+    ServiceClosed,
+    /// 89
+    UnsupportedRegion,
+    /// 90
+    DutyCycleRestricted,
+    /// 91
+    NoValidChannel,
+    /// 92
+    NoAvailableChannel,
+    /// 93
+    StatusError,
+    /// 94
+    TxTimeout,
+    /// 95
+    Rx1Timeout,
+    /// 96
+    Rx2Timeout,
+    /// 97
+    Rx1Error,
+    /// 98
+    Rx2Error,
+    /// 99
+    JoinFailed,
+    /// 100
+    DuplicatedDownlink,
+    /// 101
+    InvalidPayloadSize,
+    /// 102
+    DownlinkLost,
+    /// 103
+    AddressFail,
+    /// 104
+    InvalidMic,
+    /// Any code not listed in the manual. `ERROR` without a code is `Unknown(0)`.
+    Unknown(u16),
+}
+
+impl AtError {
+    pub fn from_code(code: u16) -> AtError {
+        match code {
+            1 => AtError::UnsupportedCommand,
+            2 => AtError::InvalidParameter,
+            3 => AtError::FlashAccess,
+            5 => AtError::UartSend,
+            41 => AtError::BleInvalidState,
+            80 => AtError::Busy,
+            81 => AtError::UnknownService,
+            82 => AtError::InvalidLoraParameter,
+            83 => AtError::InvalidFrequency,
+            84 => AtError::InvalidDataRate,
+            85 => AtError::InvalidFrequencyAndDataRate,
+            86 => AtError::NotJoined,
+            87 => AtError::PacketTooLong,
+            88 => AtError::ServiceClosed,
+            89 => AtError::UnsupportedRegion,
+            90 => AtError::DutyCycleRestricted,
+            91 => AtError::NoValidChannel,
+            92 => AtError::NoAvailableChannel,
+            93 => AtError::StatusError,
+            94 => AtError::TxTimeout,
+            95 => AtError::Rx1Timeout,
+            96 => AtError::Rx2Timeout,
+            97 => AtError::Rx1Error,
+            98 => AtError::Rx2Error,
+            99 => AtError::JoinFailed,
+            100 => AtError::DuplicatedDownlink,
+            101 => AtError::InvalidPayloadSize,
+            102 => AtError::DownlinkLost,
+            103 => AtError::AddressFail,
+            104 => AtError::InvalidMic,
+            _ => AtError::Unknown(code),
+        }
+    }
+
+    pub fn code(&self) -> u16 {
+        match self {
+            AtError::UnsupportedCommand => 1,
+            AtError::InvalidParameter => 2,
+            AtError::FlashAccess => 3,
+            AtError::UartSend => 5,
+            AtError::BleInvalidState => 41,
+            AtError::Busy => 80,
+            AtError::UnknownService => 81,
+            AtError::InvalidLoraParameter => 82,
+            AtError::InvalidFrequency => 83,
+            AtError::InvalidDataRate => 84,
+            AtError::InvalidFrequencyAndDataRate => 85,
+            AtError::NotJoined => 86,
+            AtError::PacketTooLong => 87,
+            AtError::ServiceClosed => 88,
+            AtError::UnsupportedRegion => 89,
+            AtError::DutyCycleRestricted => 90,
+            AtError::NoValidChannel => 91,
+            AtError::NoAvailableChannel => 92,
+            AtError::StatusError => 93,
+            AtError::TxTimeout => 94,
+            AtError::Rx1Timeout => 95,
+            AtError::Rx2Timeout => 96,
+            AtError::Rx1Error => 97,
+            AtError::Rx2Error => 98,
+            AtError::JoinFailed => 99,
+            AtError::DuplicatedDownlink => 100,
+            AtError::InvalidPayloadSize => 101,
+            AtError::DownlinkLost => 102,
+            AtError::AddressFail => 103,
+            AtError::InvalidMic => 104,
+            AtError::Unknown(code) => *code,
+        }
+    }
+}
+
+impl fmt::Display for AtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            AtError::UnsupportedCommand => "unsupported AT command",
+            AtError::InvalidParameter => "invalid parameter",
+            AtError::FlashAccess => "error reading or writing the flash memory",
+            AtError::UartSend => "error sending data through the UART port",
+            AtError::BleInvalidState => "BLE works in an invalid state",
+            AtError::Busy => "LoRa transceiver is busy",
+            AtError::UnknownService => "unknown LoRa service",
+            AtError::InvalidLoraParameter => "invalid LoRa parameters",
+            AtError::InvalidFrequency => "invalid LoRa frequency",
+            AtError::InvalidDataRate => "invalid LoRa data rate",
+            AtError::InvalidFrequencyAndDataRate => "invalid LoRa frequency and data rate",
+            AtError::NotJoined => "not joined",
+            AtError::PacketTooLong => "packet too long",
+            AtError::ServiceClosed => "service closed by the server",
+            AtError::UnsupportedRegion => "unsupported region",
+            AtError::DutyCycleRestricted => "duty cycle restricted",
+            AtError::NoValidChannel => "no valid LoRa channel",
+            AtError::NoAvailableChannel => "no available LoRa channel",
+            AtError::StatusError => "protocol stack status error",
+            AtError::TxTimeout => "timeout while sending the packet",
+            AtError::Rx1Timeout => "timeout while waiting in RX1 window",
+            AtError::Rx2Timeout => "timeout while waiting in RX2 window",
+            AtError::Rx1Error => "error receiving in RX1 window",
+            AtError::Rx2Error => "error receiving in RX2 window",
+            AtError::JoinFailed => "join failed",
+            AtError::DuplicatedDownlink => "duplicated downlink",
+            AtError::InvalidPayloadSize => "payload size invalid for the current data rate",
+            AtError::DownlinkLost => "many downlink packets lost",
+            AtError::AddressFail => "address fail",
+            AtError::InvalidMic => "invalid MIC",
+            AtError::Unknown(_) => "unknown error",
+        };
+        write!(f, "{} (ERROR: {})", msg, self.code())
+    }
+}
+
+impl std::error::Error for AtError {}
+
+/// A single line received from the module
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AtResponse {
+    /// `OK` followed by an optional payload, e.g. `OK V3.0.0.14.H`
+    Ok(String),
+    /// `ERROR: <code>`
+    Error(AtError),
+    /// Lines the module sends on its own, e.g. `at+recv=...`
+    Event(String),
+    /// Anything else, e.g. the continuation lines of `at+get_config=lora:status`
+    Info(String),
+}
+
+impl AtResponse {
+    pub fn parse(line: &str) -> AtResponse {
+        let line = line.trim();
+        if let Some(rest) = line.strip_prefix("OK") {
+            return AtResponse::Ok(rest.trim().to_string());
+        }
+        if let Some(rest) = line.strip_prefix("ERROR") {
+            let code = rest
+                .trim_start_matches(|c: char| c == ':' || c.is_whitespace())
+                .parse::<u16>()
+                .unwrap_or(0);
+            return AtResponse::Error(AtError::from_code(code));
+        }
+        if line.starts_with("at+") {
+            return AtResponse::Event(line.to_string());
+        }
+        AtResponse::Info(line.to_string())
+    }
+}
+
+/// The part of `at+get_config=lora:status` we care about
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoraStatus {
+    pub dev_eui: Option<String>,
+    pub app_eui: Option<String>,
+    pub app_key: Option<String>,
+    /// The ABP session
+    pub dev_addr: Option<String>,
+    pub nwks_key: Option<String>,
+    pub apps_key: Option<String>,
+}
+
+impl LoraStatus {
+    /// Parse the `Key: Value` lines of the status body. Unknown keys are ignored.
+    ///
+    /// ```text
+    /// Work Mode: LoRaWAN
+    /// Region: EU868
+    /// DevEui: 60C5A8FFFE000001
+    /// AppEui: 70B3D57ED0001234
+    /// AppKey: 3A4B5C6D7E8F90A1B2C3D4E5F6071829
+    /// DevAddr: 260B1234
+    /// AppsKey: 2B7E151628AED2A6ABF7158809CF4F3C
+    /// NwksKey: 2B7E151628AED2A6ABF7158809CF4F3C
+    /// ```
+    pub fn parse(body: &str) -> LoraStatus {
+        let mut status = LoraStatus::default();
+        for line in body.lines() {
+            let (key, value) = match line.split_once(':') {
+                Some((key, value)) => (key.trim(), value.trim().to_string()),
+                None => continue,
+            };
+            match key.to_ascii_lowercase().as_str() {
+                "deveui" => status.dev_eui = Some(value),
+                "appeui" => status.app_eui = Some(value),
+                "appkey" => status.app_key = Some(value),
+                "devaddr" => status.dev_addr = Some(value),
+                "nwkskey" => status.nwks_key = Some(value),
+                "appskey" => status.apps_key = Some(value),
+                _ => {}
+            }
+        }
+        status
+    }
+}
+
+/// The LoRa settings kept by `at backup`. A field is `None` when the module can't report it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModuleConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dev_eui: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app_eui: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region: Option<Region>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub class: Option<Class>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dr: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adr: Option<bool>,
+    /// The enabled channels
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ch_mask: Option<Vec<u8>>,
+}
+
+impl ModuleConfig {
+    /// Parse the status body of RAK811, see `LoraStatus::parse`. The channel mask is not part of it.
+    ///
+    /// ```text
+    /// Region: EU868
+    /// Class: A
+    /// AdrEnable: true
+    /// Current Datarate: 5
+    /// ```
+    pub fn parse(body: &str) -> ModuleConfig {
+        let status = LoraStatus::parse(body);
+        let mut config = ModuleConfig {
+            dev_eui: status.dev_eui,
+            app_eui: status.app_eui,
+            app_key: status.app_key,
+            ..Default::default()
+        };
+        for line in body.lines() {
+            let (key, value) = match line.split_once(':') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => continue,
+            };
+            match key.to_ascii_lowercase().as_str() {
+                "region" => config.region = Region::from_str(value, true).ok(),
+                "class" => config.class = Class::from_str(value, true).ok(),
+                "current datarate" => config.dr = value.parse().ok(),
+                "adrenable" => config.adr = value.parse().ok(),
+                _ => {}
+            }
+        }
+        config
+    }
+
+    /// The fields as text, named like in the logs. Used to compare two configs.
+    pub fn fields(&self) -> [(&'static str, Option<String>); 8] {
+        [
+            ("DevEUI", self.dev_eui.as_ref().map(|v| v.to_ascii_uppercase())),
+            ("AppEUI", self.app_eui.as_ref().map(|v| v.to_ascii_uppercase())),
+            ("AppKey", self.app_key.as_ref().map(|v| v.to_ascii_uppercase())),
+            ("Region", self.region.map(|v| v.as_str().to_string())),
+            ("Class", self.class.map(|v| format!("{:?}", v))),
+            ("DR", self.dr.map(|v| v.to_string())),
+            ("ADR", self.adr.map(|v| v.to_string())),
+            (
+                "Channel mask",
+                self.ch_mask.as_ref().map(|channels| {
+                    let mut channels = channels.clone();
+                    channels.sort_unstable();
+                    let channels: Vec<String> = channels.iter().map(|c| c.to_string()).collect();
+                    channels.join(",")
+                }),
+            ),
+        ]
+    }
+}
+
+/// The enabled channels of `at+get_config=lora:channel`, listed as
+/// `*0,on,868100000,0,5; *1,on,868300000,0,5; 2,off,868500000,0,5`
+pub fn parse_channels(body: &str) -> Vec<u8> {
+    body.split([';', '\n'])
+        .filter_map(|channel| {
+            let mut fields = channel.trim().trim_start_matches('*').split(',');
+            let number = fields.next()?.trim().parse().ok()?;
+            match fields.next()?.trim() {
+                "on" => Some(number),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// A downlink reported by RAK811 as `at+recv=<port>,<rssi>,<snr>,<len>:<data>`.
+/// When `len` is 0 the `:<data>` part is omitted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Downlink {
+    pub port: u8,
+    pub rssi: i16,
+    pub snr: i16,
+    pub len: usize,
+    /// The payload in hex
+    pub data: String,
+}
+
+impl Downlink {
+    pub fn parse(line: &str) -> Option<Downlink> {
+        let rest = line.trim().strip_prefix("at+recv=")?;
+        let (meta, data) = match rest.split_once(':') {
+            Some((meta, data)) => (meta, data.trim()),
+            None => (rest, ""),
+        };
+        let mut fields = meta.split(',').map(|f| f.trim());
+        let port = fields.next()?.parse().ok()?;
+        let rssi = fields.next()?.parse().ok()?;
+        let snr = fields.next()?.parse().ok()?;
+        let len = fields.next()?.parse().ok()?;
+        Some(Downlink {
+            port,
+            rssi,
+            snr,
+            len,
+            data: data.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every code listed in the RAK811 manual
+    const CODES: [u16; 30] = [
+        1, 2, 3, 5, 41, 80, 81, 82, 83, 84, 85, 86, 87, 88, 89, 90, 91, 92, 93, 94, 95, 96, 97, 98,
+        99, 100, 101, 102, 103, 104,
+    ];
+
+    const STATUS: &str = "Work Mode: LoRaWAN\n\
+                          Region: US915\n\
+                          Send_interval: 600s\n\
+                          Auto send status: false.\n\
+                          Join_mode: OTAA\n\
+                          DevEui: 60C5A8FFFE000001\n\
+                          AppEui: 70B3D57ED0001234\n\
+                          AppKey: 3A4B5C6D7E8F90A1B2C3D4E5F6071829\n\
+                          Class: C\n\
+                          Joined Network:false\n\
+                          IsConfirm: false\n\
+                          AdrEnable: true\n\
+                          EnableRepeaterSupport: false\n\
+                          RX2_CHANNEL_FREQUENCY: 923300000, RX2_CHANNEL_DR:8\n\
+                          RX_WINDOW_DURATION: 3000ms\n\
+                          RECEIVE_DELAY_1: 1000ms\n\
+                          RECEIVE_DELAY_2: 2000ms\n\
+                          JOIN_ACCEPT_DELAY_1: 5000ms\n\
+                          JOIN_ACCEPT_DELAY_2: 6000ms\n\
+                          Current Datarate: 2\n\
+                          Primeval Datarate: 2\n\
+                          ChannelsTxPower: 0\n\
+                          UpLinkCounter: 0\n\
+                          DownLinkCounter: 0";
+
+    #[test]
+    fn parse_ok() {
+        assert_eq!(AtResponse::parse("OK\r\n"), AtResponse::Ok(String::new()));
+        assert_eq!(
+            AtResponse::parse("OK V3.0.0.14.H\r\n"),
+            AtResponse::Ok("V3.0.0.14.H".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_error_codes() {
+        for code in CODES {
+            let error = AtError::from_code(code);
+            assert_ne!(error, AtError::Unknown(code));
+            assert_eq!(error.code(), code);
+            assert_eq!(
+                AtResponse::parse(&format!("ERROR: {}\r\n", code)),
+                AtResponse::Error(error)
+            );
+            assert_eq!(
+                AtResponse::parse(&format!("ERROR:{}", code)),
+                AtResponse::Error(error)
+            );
+        }
+        assert_eq!(
+            AtResponse::parse("ERROR: 999"),
+            AtResponse::Error(AtError::Unknown(999))
+        );
+        assert_eq!(
+            AtResponse::parse("ERROR"),
+            AtResponse::Error(AtError::Unknown(0))
+        );
+    }
+
+    #[test]
+    fn parse_events_and_info() {
+        assert_eq!(
+            AtResponse::parse("at+recv=2,-105,-12,0\r\n"),
+            AtResponse::Event("at+recv=2,-105,-12,0".to_string())
+        );
+        assert_eq!(
+            AtResponse::parse("Initialization OK"),
+            AtResponse::Info("Initialization OK".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_status() {
+        let status = LoraStatus::parse(STATUS);
+        assert_eq!(
+            status,
+            LoraStatus {
+                dev_eui: Some("60C5A8FFFE000001".to_string()),
+                app_eui: Some("70B3D57ED0001234".to_string()),
+                app_key: Some("3A4B5C6D7E8F90A1B2C3D4E5F6071829".to_string()),
+                ..Default::default()
+            }
+        );
+        let abp = LoraStatus::parse("DevAddr: 260B1234\nAppsKey: 01\nNwksKey: 02\n");
+        assert_eq!(abp.dev_addr.as_deref(), Some("260B1234"));
+        assert_eq!(abp.apps_key.as_deref(), Some("01"));
+        assert_eq!(abp.nwks_key.as_deref(), Some("02"));
+    }
+
+    #[test]
+    fn parse_module_config() {
+        let config = ModuleConfig::parse(STATUS);
+        assert_eq!(
+            config,
+            ModuleConfig {
+                dev_eui: Some("60C5A8FFFE000001".to_string()),
+                app_eui: Some("70B3D57ED0001234".to_string()),
+                app_key: Some("3A4B5C6D7E8F90A1B2C3D4E5F6071829".to_string()),
+                region: Some(Region::Us915),
+                class: Some(Class::C),
+                dr: Some(2),
+                adr: Some(true),
+                ch_mask: None,
+            }
+        );
+        // unknown values are left out
+        let config = ModuleConfig::parse("Region: XX123\nClass: D\nCurrent Datarate: x\n");
+        assert_eq!(config, ModuleConfig::default());
+    }
+
+    #[test]
+    fn parse_downlink() {
+        assert_eq!(
+            Downlink::parse("at+recv=2,-105,-12,4:DEADBEEF\r\n"),
+            Some(Downlink {
+                port: 2,
+                rssi: -105,
+                snr: -12,
+                len: 4,
+                data: "DEADBEEF".to_string(),
+            })
+        );
+        // no data, e.g. the ack of a confirmed uplink
+        assert_eq!(
+            Downlink::parse("at+recv=0,-40,7,0"),
+            Some(Downlink {
+                port: 0,
+                rssi: -40,
+                snr: 7,
+                len: 0,
+                data: String::new(),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_malformed_downlink() {
+        for line in [
+            "",
+            "OK",
+            "at+recv=",
+            "at+recv=2,-105,-12",
+            "at+recv=2,-105,-12:AA",
+            "at+recv=x,-105,-12,1:AA",
+            "at+recv=256,-105,-12,1:AA",
+            "at+recv=2,-105,-12,-1:AA",
+            "at+send=lora:2:AA",
+        ] {
+            assert_eq!(Downlink::parse(line), None, "{}", line);
+        }
+    }
+}
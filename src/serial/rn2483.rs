@@ -0,0 +1,254 @@
+use super::modem::{check_field, listen_lines, on_off};
+use super::{AtError, AtSession, Downlink, LoraModem, LoraStatus, SerialError};
+use crate::lora::{Class, JoinMode, Region};
+use log::{info, warn};
+use std::time::{Duration, Instant};
+
+/// How long `send` waits for the second response, including the RX windows
+const SEND_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Microchip RN2483 / RN2903.
+///
+/// Commands are plain words (`mac set deveui <hex>`) answered by a single line,
+/// `ok` or an error keyword. `mac join` and `mac tx` answer twice: `ok` when the
+/// command is accepted, then the result (`accepted`, `denied`, `mac_tx_ok`, ...).
+/// `mac set` only changes the RAM, every setting is followed by `mac save`.
+/// The join mode and confirm aren't settings, they are given with every
+/// `mac join` and `mac tx`.
+pub struct Rn2483 {
+    session: AtSession,
+}
+
+impl Rn2483 {
+    pub fn new(session: AtSession) -> Rn2483 {
+        Rn2483 { session }
+    }
+
+    /// `mac tx uncnf <port> <hex>` (or `cnf`), `ok` then `mac_tx_ok` or `mac_rx ...`
+    fn tx(&mut self, kind: &str, port: u8, payload: &str) -> Result<(), SerialError> {
+        self.send_read(&format!("mac tx {} {} {}", kind, port, payload))?;
+        let result = self.read_response(SEND_TIMEOUT)?;
+        if result.starts_with("mac_rx") {
+            info!("Event: {}", result);
+        }
+        Ok(())
+    }
+
+    /// Send the command and return the first line of the response
+    fn send_read(&mut self, command: &str) -> Result<String, SerialError> {
+        self.session.write_command(&format!("{}\r\n", command))?;
+        let timeout = self.session.timeout();
+        self.read_response(timeout)
+    }
+
+    /// Read the next non-empty line. Error keywords become `SerialError::Device`.
+    fn read_response(&mut self, timeout: Duration) -> Result<String, SerialError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let line = match self.session.read_line(deadline)? {
+                Some(line) => line,
+                None => return Err(SerialError::Timeout),
+            };
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            return match parse_error(line) {
+                Some(e) => Err(SerialError::Device(e)),
+                None => Ok(line.to_string()),
+            };
+        }
+    }
+
+    /// `mac set <name> <value>` followed by `mac save`
+    fn set(&mut self, name: &str, value: &str) -> Result<(), SerialError> {
+        self.send_read(&format!("mac set {} {}", name, value))?;
+        self.send_read("mac save")?;
+        Ok(())
+    }
+}
+
+impl LoraModem for Rn2483 {
+    /// `sys get ver` answers e.g. `RN2483 1.0.5 Oct 31 2018 15:06:52`
+    fn version(&mut self) -> Result<String, SerialError> {
+        self.send_read("sys get ver")
+    }
+
+    fn set_dev_eui(&mut self, dev_eui: &str) -> Result<(), SerialError> {
+        self.set("deveui", dev_eui)
+    }
+
+    fn set_app_eui(&mut self, app_eui: &str) -> Result<(), SerialError> {
+        self.set("appeui", app_eui)
+    }
+
+    fn set_app_key(&mut self, app_key: &str) -> Result<(), SerialError> {
+        self.set("appkey", app_key)
+    }
+
+    /// The keys can't be read back from RN2483
+    fn status(&mut self) -> Result<LoraStatus, SerialError> {
+        Ok(LoraStatus {
+            dev_eui: Some(self.send_read("mac get deveui")?),
+            app_eui: Some(self.send_read("mac get appeui")?),
+            dev_addr: Some(self.send_read("mac get devaddr")?),
+            ..Default::default()
+        })
+    }
+
+    /// `sys get hweui`, which stays even after `mac set deveui`
+    fn hw_dev_eui(&mut self) -> Result<Option<String>, SerialError> {
+        Ok(Some(self.send_read("sys get hweui")?))
+    }
+
+    /// OTAA unless joined with `join_as`
+    fn join(&mut self, timeout: Duration) -> Result<(), SerialError> {
+        self.join_as(JoinMode::Otaa, timeout)
+    }
+
+    /// `mac join otaa` (or `abp`), `ok` then `accepted` or `denied`
+    fn join_as(&mut self, mode: JoinMode, timeout: Duration) -> Result<(), SerialError> {
+        let mode = match mode {
+            JoinMode::Otaa => "otaa",
+            JoinMode::Abp => "abp",
+        };
+        self.send_read(&format!("mac join {}", mode))?;
+        match self.read_response(timeout)?.as_str() {
+            "accepted" => Ok(()),
+            other => {
+                warn!("Unexpected join result: {}", other);
+                Err(SerialError::Device(AtError::JoinFailed))
+            }
+        }
+    }
+
+    fn send(&mut self, port: u8, payload: &str) -> Result<(), SerialError> {
+        self.tx("uncnf", port, payload)
+    }
+
+    fn send_confirmed(&mut self, port: u8, payload: &str) -> Result<(), SerialError> {
+        self.tx("cnf", port, payload)
+    }
+
+    /// Downlinks are printed as `mac_rx <port> <hex>` after an uplink.
+    /// RN2483 doesn't report RSSI and SNR with them, both are left as 0.
+    fn listen(&mut self, on_downlink: &mut dyn FnMut(Downlink)) -> Result<(), SerialError> {
+        listen_lines(&mut self.session, &mut parse_downlink, on_downlink)
+    }
+
+    /// `mac reset 868` or `mac reset 433`. Only RN2483 has bands to choose from
+    /// and the reset brings every other setting back to its default.
+    fn set_region(&mut self, region: Region) -> Result<(), SerialError> {
+        let band = match region {
+            Region::Eu868 => "868",
+            Region::Eu433 => "433",
+            _ => {
+                return Err(SerialError::Unsupported(
+                    "region other than EU868 and EU433",
+                ))
+            }
+        };
+        self.send_read(&format!("mac reset {}", band))?;
+        Ok(())
+    }
+
+    /// Class A and C only
+    fn set_class(&mut self, class: Class) -> Result<(), SerialError> {
+        let value = match class {
+            Class::A => "a",
+            Class::B => return Err(SerialError::Unsupported("class B")),
+            Class::C => "c",
+        };
+        self.set("class", value)
+    }
+
+    fn set_dr(&mut self, dr: u8) -> Result<(), SerialError> {
+        self.set("dr", &dr.to_string())
+    }
+
+    fn set_adr(&mut self, adr: bool) -> Result<(), SerialError> {
+        self.set("adr", on_off(adr))
+    }
+
+    /// `mac set pwridx`, the index into the power table of the band
+    fn set_tx_power(&mut self, tx_power: u8) -> Result<(), SerialError> {
+        self.set("pwridx", &tx_power.to_string())
+    }
+
+    fn set_dev_addr(&mut self, dev_addr: &str) -> Result<(), SerialError> {
+        self.set("devaddr", dev_addr)
+    }
+
+    fn set_nwks_key(&mut self, nwks_key: &str) -> Result<(), SerialError> {
+        self.set("nwkskey", nwks_key)
+    }
+
+    fn set_apps_key(&mut self, apps_key: &str) -> Result<(), SerialError> {
+        self.set("appskey", apps_key)
+    }
+
+    /// `mac set ch status <channel> on|off`
+    fn set_ch_mask(&mut self, channel: u8, enabled: bool) -> Result<(), SerialError> {
+        self.set("ch status", &format!("{} {}", channel, on_off(enabled)))
+    }
+
+    /// Only the DevEUI can be read back, the AppKey is trusted as written
+    fn verify_keys(&mut self, dev_eui: &str, _app_key: &str) -> Result<(), SerialError> {
+        let actual = self.send_read("mac get deveui")?;
+        if !actual.eq_ignore_ascii_case(dev_eui) {
+            return Err(SerialError::Mismatch {
+                name: "DevEUI",
+                expected: dev_eui.to_string(),
+                actual: Some(actual),
+            });
+        }
+        warn!("RN2483 can't read the AppKey back, it is not verified");
+        Ok(())
+    }
+
+    /// Only the DevEUI and DevAddr can be read back, the session keys are trusted as written
+    fn verify_abp_keys(
+        &mut self,
+        dev_eui: &str,
+        dev_addr: &str,
+        _nwks_key: &str,
+        _apps_key: &str,
+    ) -> Result<(), SerialError> {
+        let status = self.status()?;
+        check_field("DevEUI", dev_eui, status.dev_eui)?;
+        check_field("DevAddr", dev_addr, status.dev_addr)?;
+        warn!("RN2483 can't read the session keys back, they are not verified");
+        Ok(())
+    }
+}
+
+/// `invalid_param` and the other error keywords of RN2483
+fn parse_error(line: &str) -> Option<AtError> {
+    let e = match line {
+        "invalid_param" => AtError::InvalidParameter,
+        "keys_not_init" => AtError::InvalidLoraParameter,
+        "no_free_ch" => AtError::NoAvailableChannel,
+        "busy" => AtError::Busy,
+        "not_joined" => AtError::NotJoined,
+        "silent" | "mac_paused" | "frame_counter_err_rejoin_needed" => AtError::StatusError,
+        "invalid_data_len" => AtError::InvalidPayloadSize,
+        "denied" => AtError::JoinFailed,
+        "mac_err" => AtError::TxTimeout,
+        _ => return None,
+    };
+    Some(e)
+}
+
+/// `mac_rx <port> <hex>`
+fn parse_downlink(line: &str) -> Option<Downlink> {
+    let mut fields = line.trim().strip_prefix("mac_rx ")?.split_whitespace();
+    let port = fields.next()?.parse().ok()?;
+    let data = fields.next().unwrap_or("").to_string();
+    Some(Downlink {
+        port,
+        rssi: 0,
+        snr: 0,
+        len: data.len() / 2,
+        data,
+    })
+}
@@ -0,0 +1,245 @@
+use super::modem::{bool_value, listen_lines};
+use super::{AtError, AtSession, Downlink, LoraModem, LoraStatus, ModuleConfig, SerialError};
+use crate::lora::{Class, JoinMode, Region};
+use clap::ValueEnum;
+use log::info;
+use std::time::{Duration, Instant};
+
+/// `AT+BAND` of each region
+const BANDS: [(Region, &str); 8] = [
+    (Region::Eu433, "0"),
+    (Region::Cn470, "1"),
+    (Region::In865, "3"),
+    (Region::Eu868, "4"),
+    (Region::Us915, "5"),
+    (Region::Au915, "6"),
+    (Region::Kr920, "7"),
+    (Region::As923, "8"),
+];
+
+/// RAK3172 and other modules running the RUI3 firmware.
+///
+/// Commands are upper case (`AT+DEVEUI=...`), answered by `OK` or an `AT_*`
+/// error, and things happening later are reported as `+EVT:...` lines.
+pub struct Rui3 {
+    session: AtSession,
+}
+
+impl Rui3 {
+    pub fn new(session: AtSession) -> Rui3 {
+        Rui3 { session }
+    }
+
+    /// Send the command and collect the lines until `OK` or an `AT_*` error.
+    /// The echo of the command and `+EVT` lines are not part of the body.
+    fn send_read(&mut self, command: &str) -> Result<Vec<String>, SerialError> {
+        self.session.write_command(&format!("{}\r\n", command))?;
+        let deadline = Instant::now() + self.session.timeout();
+        let mut body: Vec<String> = Vec::new();
+        loop {
+            let line = match self.session.read_line(deadline)? {
+                Some(line) => line,
+                None => return Err(SerialError::Timeout),
+            };
+            let line = line.trim();
+            if line == "OK" {
+                return Ok(body);
+            }
+            if let Some(e) = parse_error(line) {
+                return Err(SerialError::Device(e));
+            }
+            if line.starts_with("+EVT:") {
+                info!("Event: {}", line);
+            } else if !line.is_empty() && line != command {
+                body.push(line.to_string());
+            }
+        }
+    }
+
+    /// `AT+<name>=<value>`
+    fn set(&mut self, name: &str, value: &str) -> Result<(), SerialError> {
+        self.send_read(&format!("AT+{}={}", name, value))?;
+        Ok(())
+    }
+
+    /// `AT+<name>=?`. Newer firmware repeats the command in front of the value,
+    /// e.g. `AT+DEVEUI=AC1F09FFFE000001`, older firmware prints the value alone.
+    fn query(&mut self, name: &str) -> Result<String, SerialError> {
+        let body = self.send_read(&format!("AT+{}=?", name))?;
+        let prefix = format!("AT+{}=", name);
+        let value = body
+            .first()
+            .map(|line| line.trim_start_matches(prefix.as_str()));
+        Ok(value.unwrap_or_default().to_string())
+    }
+}
+
+impl LoraModem for Rui3 {
+    fn version(&mut self) -> Result<String, SerialError> {
+        self.query("VER")
+    }
+
+    fn set_dev_eui(&mut self, dev_eui: &str) -> Result<(), SerialError> {
+        self.set("DEVEUI", dev_eui)
+    }
+
+    fn set_app_eui(&mut self, app_eui: &str) -> Result<(), SerialError> {
+        self.set("APPEUI", app_eui)
+    }
+
+    fn set_app_key(&mut self, app_key: &str) -> Result<(), SerialError> {
+        self.set("APPKEY", app_key)
+    }
+
+    /// RUI3 has no single status command, so the keys are queried one by one
+    fn status(&mut self) -> Result<LoraStatus, SerialError> {
+        Ok(LoraStatus {
+            dev_eui: Some(self.query("DEVEUI")?),
+            app_eui: Some(self.query("APPEUI")?),
+            app_key: Some(self.query("APPKEY")?),
+            dev_addr: Some(self.query("DEVADDR")?),
+            nwks_key: Some(self.query("NWKSKEY")?),
+            apps_key: Some(self.query("APPSKEY")?),
+        })
+    }
+
+    /// The channel mask of RUI3 is per sub-band, so it is not part of it
+    fn config(&mut self) -> Result<ModuleConfig, SerialError> {
+        let band = self.query("BAND")?;
+        Ok(ModuleConfig {
+            dev_eui: Some(self.query("DEVEUI")?),
+            app_eui: Some(self.query("APPEUI")?),
+            app_key: Some(self.query("APPKEY")?),
+            region: BANDS.iter().find(|(_, b)| *b == band).map(|(r, _)| *r),
+            class: Class::from_str(&self.query("CLASS")?, true).ok(),
+            dr: self.query("DR")?.parse().ok(),
+            adr: Some(self.query("ADR")? == "1"),
+            ch_mask: None,
+        })
+    }
+
+    /// `AT+JOIN=1:0:10:1` asks for a single attempt without auto-join, so the
+    /// retries stay under the control of `join_retry`. The module answers `OK`
+    /// right away and reports the result as `+EVT:JOINED` or `+EVT:JOIN_FAILED_*`.
+    fn join(&mut self, timeout: Duration) -> Result<(), SerialError> {
+        let command = "AT+JOIN=1:0:10:1";
+        self.session.write_command(&format!("{}\r\n", command))?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            let line = match self.session.read_line(deadline)? {
+                Some(line) => line,
+                None => return Err(SerialError::Timeout),
+            };
+            let line = line.trim();
+            if let Some(e) = parse_error(line) {
+                return Err(SerialError::Device(e));
+            }
+            if line == "+EVT:JOINED" {
+                return Ok(());
+            }
+            if line.starts_with("+EVT:JOIN_FAILED") {
+                return Err(SerialError::Device(AtError::JoinFailed));
+            }
+            if line.starts_with("+EVT:") {
+                info!("Event: {}", line);
+            }
+        }
+    }
+
+    /// `AT+SEND=<port>:<hex>`
+    fn send(&mut self, port: u8, payload: &str) -> Result<(), SerialError> {
+        self.set("SEND", &format!("{}:{}", port, payload))
+    }
+
+    fn listen(&mut self, on_downlink: &mut dyn FnMut(Downlink)) -> Result<(), SerialError> {
+        listen_lines(&mut self.session, &mut parse_downlink, on_downlink)
+    }
+
+    fn set_join_mode(&mut self, mode: JoinMode) -> Result<(), SerialError> {
+        let value = match mode {
+            JoinMode::Abp => "0",
+            JoinMode::Otaa => "1",
+        };
+        self.set("NJM", value)
+    }
+
+    fn set_region(&mut self, region: Region) -> Result<(), SerialError> {
+        let band = BANDS
+            .iter()
+            .find(|(r, _)| *r == region)
+            .map(|(_, band)| *band)
+            .ok_or(SerialError::Unsupported("region"))?;
+        self.set("BAND", band)
+    }
+
+    fn set_class(&mut self, class: Class) -> Result<(), SerialError> {
+        let value = match class {
+            Class::A => "A",
+            Class::B => "B",
+            Class::C => "C",
+        };
+        self.set("CLASS", value)
+    }
+
+    fn set_confirm(&mut self, confirm: bool) -> Result<(), SerialError> {
+        self.set("CFM", bool_value(confirm))
+    }
+
+    fn set_dr(&mut self, dr: u8) -> Result<(), SerialError> {
+        self.set("DR", &dr.to_string())
+    }
+
+    fn set_adr(&mut self, adr: bool) -> Result<(), SerialError> {
+        self.set("ADR", bool_value(adr))
+    }
+
+    fn set_tx_power(&mut self, tx_power: u8) -> Result<(), SerialError> {
+        self.set("TXP", &tx_power.to_string())
+    }
+
+    fn set_dev_addr(&mut self, dev_addr: &str) -> Result<(), SerialError> {
+        self.set("DEVADDR", dev_addr)
+    }
+
+    fn set_nwks_key(&mut self, nwks_key: &str) -> Result<(), SerialError> {
+        self.set("NWKSKEY", nwks_key)
+    }
+
+    fn set_apps_key(&mut self, apps_key: &str) -> Result<(), SerialError> {
+        self.set("APPSKEY", apps_key)
+    }
+}
+
+/// `AT_PARAM_ERROR` and the other `AT_*` errors of RUI3
+fn parse_error(line: &str) -> Option<AtError> {
+    let e = match line {
+        "AT_ERROR" => AtError::Unknown(0),
+        "AT_PARAM_ERROR" | "AT_TEST_PARAM_OVERFLOW" => AtError::InvalidParameter,
+        "AT_BUSY_ERROR" => AtError::Busy,
+        "AT_NO_NETWORK_JOINED" => AtError::NotJoined,
+        "AT_RX_ERROR" => AtError::Rx1Error,
+        "AT_COMMAND_NOT_FOUND" | "AT_MODE_NO_SUPPORT" => AtError::UnsupportedCommand,
+        "AT_NO_CLASSB_ENABLE" => AtError::StatusError,
+        _ => return None,
+    };
+    Some(e)
+}
+
+/// `+EVT:RX_1:<rssi>:<snr>:UNICAST:<port>:<hex>`, the window may also be `RX_2` or `RX_C`
+fn parse_downlink(line: &str) -> Option<Downlink> {
+    let rest = line.trim().strip_prefix("+EVT:RX_")?;
+    let mut fields = rest.split(':');
+    let _window = fields.next()?;
+    let rssi = fields.next()?.trim().parse().ok()?;
+    let snr = fields.next()?.trim().parse().ok()?;
+    let _cast = fields.next()?;
+    let port = fields.next()?.trim().parse().ok()?;
+    let data = fields.next().unwrap_or("").trim().to_string();
+    Some(Downlink {
+        port,
+        rssi,
+        snr,
+        len: data.len() / 2,
+        data,
+    })
+}
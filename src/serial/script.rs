@@ -0,0 +1,303 @@
+use super::{AtSession, SerialError};
+use crate::utils::escape_string;
+use log::{debug, info};
+use regex::Regex;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::time::{Duration, Instant};
+
+/// The timeout of `expect` until the script sets another one
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A step of a script, see `parse`
+#[derive(Debug, Clone)]
+pub enum Step {
+    /// Send the line, followed by `\r\n`
+    Send(String),
+    /// Wait for a line matching the regex
+    Expect(String),
+    /// Change the timeout of the following `expect`s
+    Timeout(Duration),
+}
+
+/// Why a script stopped. `line` is the line number in the script.
+#[derive(Debug)]
+pub enum ScriptError {
+    Parse { line: usize, msg: String },
+    Serial { line: usize, error: SerialError },
+    Regex { line: usize, error: regex::Error },
+    UnknownVariable { line: usize, name: String },
+    Timeout { line: usize, pattern: String },
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptError::Parse { line, msg } => write!(f, "line {}: {}", line, msg),
+            ScriptError::Serial { line, error } => write!(f, "line {}: {}", line, error),
+            ScriptError::Regex { line, error } => write!(f, "line {}: {}", line, error),
+            ScriptError::UnknownVariable { line, name } => {
+                write!(f, "line {}: unknown variable {}", line, name)
+            }
+            ScriptError::Timeout { line, pattern } => {
+                write!(f, "line {}: timeout waiting for {}", line, pattern)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+/// Parse a script. Each line is a step, blank lines and lines starting with `#` are skipped.
+///
+/// ```text
+/// # read the DevEUI and write it back
+/// timeout 2
+/// send at+get_config=lora:status
+/// expect DevEui: (?P<dev_eui>[0-9A-Fa-f]{16})
+/// send at+set_config=lora:dev_eui:${dev_eui}
+/// expect ^OK
+/// ```
+///
+/// `expect` takes a regex, its named groups are captured as variables.
+/// `${name}` is replaced by the variable in `send` and `expect`.
+/// `timeout` is in seconds.
+pub fn parse(script: &str) -> Result<Vec<(usize, Step)>, ScriptError> {
+    let mut steps = Vec::new();
+    for (n, line) in script.lines().enumerate() {
+        let line_no = n + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (keyword, arg) = line.split_once(' ').unwrap_or((line, ""));
+        let arg = arg.trim();
+        let step = match keyword {
+            "send" => Step::Send(arg.to_string()),
+            "expect" if !arg.is_empty() => Step::Expect(arg.to_string()),
+            "timeout" => match arg.parse().map(Duration::try_from_secs_f32) {
+                Ok(Ok(timeout)) if !timeout.is_zero() => Step::Timeout(timeout),
+                _ => {
+                    return Err(ScriptError::Parse {
+                        line: line_no,
+                        msg: format!("invalid timeout {}", arg),
+                    })
+                }
+            },
+            _ => {
+                return Err(ScriptError::Parse {
+                    line: line_no,
+                    msg: format!("unknown step {}", line),
+                })
+            }
+        };
+        steps.push((line_no, step));
+    }
+    Ok(steps)
+}
+
+/// Replace `${name}` by the variable. `escape` is applied to the values.
+fn substitute(
+    text: &str,
+    vars: &HashMap<String, String>,
+    escape: fn(&str) -> String,
+    line: usize,
+) -> Result<String, ScriptError> {
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let name = &rest[start + 2..end];
+        let value = vars.get(name).ok_or_else(|| ScriptError::UnknownVariable {
+            line,
+            name: name.to_string(),
+        })?;
+        result.push_str(&rest[..start]);
+        result.push_str(&escape(value));
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Run the steps in order and return the variables captured.
+/// `vars` are the variables set before the script starts.
+pub fn run(
+    session: &mut AtSession,
+    steps: &[(usize, Step)],
+    mut vars: HashMap<String, String>,
+) -> Result<HashMap<String, String>, ScriptError> {
+    let mut timeout = DEFAULT_TIMEOUT;
+    for (line, step) in steps {
+        let line = *line;
+        match step {
+            Step::Send(text) => {
+                let text = substitute(text, &vars, |v| v.to_string(), line)?;
+                info!("{}: send {}", line, text);
+                session
+                    .write_command(&format!("{}\r\n", text))
+                    .map_err(|error| ScriptError::Serial { line, error })?;
+            }
+            Step::Expect(pattern) => {
+                let pattern = substitute(pattern, &vars, regex::escape, line)?;
+                let re =
+                    Regex::new(&pattern).map_err(|error| ScriptError::Regex { line, error })?;
+                let deadline = Instant::now() + timeout;
+                let captures = loop {
+                    let received = session
+                        .read_line(deadline)
+                        .map_err(|error| ScriptError::Serial { line, error })?;
+                    let received = match received {
+                        Some(received) => received,
+                        None => return Err(ScriptError::Timeout { line, pattern }),
+                    };
+                    let received = received.trim_end_matches(['\r', '\n']);
+                    if let Some(captures) = re.captures(received) {
+                        info!("{}: got {}", line, escape_string(received.to_string()));
+                        break captures
+                            .iter()
+                            .zip(re.capture_names())
+                            .filter_map(|(value, name)| Some((name?, value?.as_str())))
+                            .map(|(name, value)| (name.to_string(), value.to_string()))
+                            .collect::<Vec<_>>();
+                    }
+                    debug!("{}: skip {}", line, escape_string(received.to_string()));
+                };
+                for (name, value) in captures {
+                    info!("{}: {} = {}", line, name, value);
+                    vars.insert(name, value);
+                }
+            }
+            Step::Timeout(t) => timeout = *t,
+        }
+    }
+    Ok(vars)
+}
+
+/// Read the script in `file` and run it
+pub fn run_file(
+    session: &mut AtSession,
+    file: &str,
+    vars: HashMap<String, String>,
+) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+    let script = fs::read_to_string(file)?;
+    let steps = parse(&script)?;
+    Ok(run(session, &steps, vars)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// Open a session replaying `transcript`
+    fn replay(name: &str, transcript: &str) -> AtSession {
+        let file = std::env::temp_dir().join(format!("script-{}-{}.log", name, std::process::id()));
+        fs::write(&file, transcript).unwrap();
+        let path = format!("replay://{}", file.display());
+        AtSession::open(&path, 115200, Duration::from_secs(1)).unwrap()
+    }
+
+    #[test]
+    fn parse_steps() {
+        let steps = parse("# comment\n\nsend at+version\nexpect ^OK\ntimeout 0.5\n").unwrap();
+        let lines: Vec<usize> = steps.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, [3, 4, 5]);
+        assert!(matches!(&steps[0].1, Step::Send(text) if text == "at+version"));
+        assert!(matches!(&steps[1].1, Step::Expect(pattern) if pattern == "^OK"));
+        assert!(matches!(steps[2].1, Step::Timeout(t) if t == Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn parse_bad_input() {
+        for script in [
+            "wait 1",
+            "expect",
+            "timeout",
+            "timeout x",
+            "timeout 0",
+            "timeout -1",
+        ] {
+            assert!(matches!(
+                parse(script),
+                Err(ScriptError::Parse { line: 1, .. })
+            ));
+        }
+        // not a Duration, from_secs_f32 would panic
+        for script in ["timeout inf", "timeout 1e40", "timeout NaN"] {
+            assert!(matches!(
+                parse(script),
+                Err(ScriptError::Parse { line: 1, .. })
+            ));
+        }
+        assert!(matches!(
+            parse("send at\nbogus"),
+            Err(ScriptError::Parse { line: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn substitute_variables() {
+        let vars = vars(&[("eui", "0102"), ("dot", "a.b")]);
+        let text = substitute("x ${eui} ${dot}", &vars, |v| v.to_string(), 1).unwrap();
+        assert_eq!(text, "x 0102 a.b");
+        let pattern = substitute("^${dot}$", &vars, regex::escape, 1).unwrap();
+        assert_eq!(pattern, "^a\\.b$");
+        // not a variable without the closing brace
+        assert_eq!(
+            substitute("${eui", &vars, |v| v.to_string(), 1).unwrap(),
+            "${eui"
+        );
+        assert!(matches!(
+            substitute("${missing}", &vars, |v| v.to_string(), 7),
+            Err(ScriptError::UnknownVariable { line: 7, name }) if name == "missing"
+        ));
+    }
+
+    #[test]
+    fn send_expect_and_capture() {
+        let mut session = replay(
+            "capture",
+            "0 > at+get_config=lora:status\\r\\n\n\
+             0 < OK Work Mode: LoRaWAN\\r\\n\n\
+             0 < DevEui: 0102030405060708\\r\\n\n\
+             0 > at+set_config=lora:dev_eui:0102030405060708\\r\\n\n\
+             0 < OK\\r\\n\n",
+        );
+        let steps = parse(
+            "send at+get_config=lora:status\n\
+             expect DevEui: (?P<dev_eui>[0-9A-Fa-f]{16})\n\
+             send at+set_config=lora:dev_eui:${dev_eui}\n\
+             expect ^OK",
+        )
+        .unwrap();
+        let vars = run(&mut session, &steps, vars(&[("given", "1")])).unwrap();
+        assert_eq!(
+            vars,
+            self::vars(&[("given", "1"), ("dev_eui", "0102030405060708")])
+        );
+    }
+
+    #[test]
+    fn expect_times_out() {
+        let mut session = replay(
+            "timeout",
+            "0 > at+version\\r\\n\n0 < OK V3.0.0.14.H\\r\\n\n",
+        );
+        let steps = parse("timeout 0.2\nsend at+version\nexpect ^ERROR").unwrap();
+        assert!(matches!(
+            run(&mut session, &steps, HashMap::new()),
+            Err(ScriptError::Timeout { line: 3, .. })
+        ));
+    }
+}
@@ -0,0 +1,191 @@
+use super::probe;
+use super::AtSession;
+use crate::utils::escape_string;
+use log::{error, info, LevelFilter};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, ExternalPrinter, Helper};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long the serial thread waits for a line before checking for a new command
+const POLL: Duration = Duration::from_millis(100);
+
+/// The commands of "RAK811 Module AT Command Manual", completed with <Tab>
+const RAK811_COMMANDS: &[&str] = &[
+    "at+version",
+    "at+help",
+    "at+run",
+    "at+join",
+    "at+send=lora:",
+    "at+send=lorap2p:",
+    "at+get_config=device:status",
+    "at+get_config=device:gpio:",
+    "at+get_config=device:adc:",
+    "at+get_config=lora:status",
+    "at+get_config=lora:channel",
+    "at+set_config=device:restart",
+    "at+set_config=device:boot",
+    "at+set_config=device:sleep:",
+    "at+set_config=device:uart:",
+    "at+set_config=device:uart_mode:",
+    "at+set_config=device:gpio:",
+    "at+set_config=lora:work_mode:",
+    "at+set_config=lora:join_mode:",
+    "at+set_config=lora:class:",
+    "at+set_config=lora:region:",
+    "at+set_config=lora:confirm:",
+    "at+set_config=lora:dev_eui:",
+    "at+set_config=lora:app_eui:",
+    "at+set_config=lora:app_key:",
+    "at+set_config=lora:dev_addr:",
+    "at+set_config=lora:apps_key:",
+    "at+set_config=lora:nwks_key:",
+    "at+set_config=lora:multicastenable:",
+    "at+set_config=lora:multicast_dev_addr:",
+    "at+set_config=lora:multicast_apps_key:",
+    "at+set_config=lora:multicast_nwks_key:",
+    "at+set_config=lora:ch_mask:",
+    "at+set_config=lora:dr:",
+    "at+set_config=lora:adr:",
+    "at+set_config=lora:tx_power:",
+    "at+set_config=lora:send_repeat_cnt:",
+    "at+set_config=lora:default_parameters",
+    "at+set_config=lorap2p:",
+    "exit",
+];
+
+struct AtHelper;
+
+impl Completer for AtHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let prefix = line[..pos].to_ascii_lowercase();
+        let candidates = RAK811_COMMANDS
+            .iter()
+            .filter(|command| command.starts_with(&prefix))
+            .map(|command| command.to_string())
+            .collect();
+        Ok((0, candidates))
+    }
+}
+
+impl Hinter for AtHelper {
+    type Hint = String;
+}
+
+impl Highlighter for AtHelper {}
+
+impl Validator for AtHelper {}
+
+impl Helper for AtHelper {}
+
+/// Type AT commands by hand. Every line from the module is printed as it
+/// arrives, escaped, including the ones it sends on its own like `at+recv`.
+pub fn handle_shell(path: &str, baud: Option<u32>) {
+    let baud = probe::detect_baud(path, baud);
+    let session = match AtSession::open(path, baud, POLL) {
+        Ok(session) => session,
+        Err(e) => {
+            error!("Failed to open {}: {}", path, e);
+            return;
+        }
+    };
+    let mut rl: Editor<AtHelper, DefaultHistory> = Editor::new().expect("Failed to open terminal");
+    rl.set_helper(Some(AtHelper));
+    let history = history_path();
+    if let Some(history) = &history {
+        // there is no history on the first run
+        let _ = rl.load_history(history);
+    }
+    let print: Box<dyn FnMut(String) + Send> = match rl.create_external_printer() {
+        Ok(mut printer) => Box::new(move |msg| {
+            if printer.print(msg.clone()).is_err() {
+                println!("{}", msg);
+            }
+        }),
+        Err(_) => Box::new(|msg| println!("{}", msg)),
+    };
+    // the traffic is printed by the shell already
+    log::set_max_level(LevelFilter::Info);
+    info!(
+        "Connected to {} at {} baud. Press <Tab> to complete, Ctrl-D to quit.",
+        path, baud
+    );
+
+    let (commands, rx) = mpsc::channel();
+    let serial = thread::spawn(move || serial_loop(session, rx, print));
+    loop {
+        match rl.readline("> ") {
+            Ok(line) => {
+                let line = line.trim().to_string();
+                if line.is_empty() {
+                    continue;
+                }
+                let _ = rl.add_history_entry(line.as_str());
+                if line == "exit" || commands.send(line).is_err() {
+                    break;
+                }
+            }
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                error!("{}", e);
+                break;
+            }
+        }
+    }
+    drop(commands);
+    let _ = serial.join();
+    if let Some(history) = &history {
+        if let Err(e) = rl.save_history(history) {
+            error!("Failed to save history to {:?}: {}", history, e);
+        }
+    }
+}
+
+/// Send the commands from `rx` and print every line received, until `rx` is closed
+fn serial_loop(
+    mut session: AtSession,
+    rx: Receiver<String>,
+    mut print: Box<dyn FnMut(String) + Send>,
+) {
+    loop {
+        match rx.try_recv() {
+            Ok(command) => {
+                if let Err(e) = session.write_command(&format!("{}\r\n", command)) {
+                    print(format!("! {}", e));
+                    return;
+                }
+            }
+            Err(TryRecvError::Empty) => {}
+            Err(TryRecvError::Disconnected) => return,
+        }
+        match session.read_line(Instant::now() + POLL) {
+            Ok(Some(line)) => print(format!("< {}", escape_string(line))),
+            Ok(None) => {}
+            Err(e) => {
+                print(format!("! {}", e));
+                return;
+            }
+        }
+    }
+}
+
+/// Next to the config file
+fn history_path() -> Option<PathBuf> {
+    confy::get_configuration_file_path("chirpstack-utils", None)
+        .ok()
+        .map(|file| file.with_file_name("at_history.txt"))
+}
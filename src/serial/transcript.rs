@@ -0,0 +1,312 @@
+use crate::utils::{escape_string, unescape_string};
+use log::warn;
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Where the transcripts are written, if recording is enabled
+static RECORD_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Record every session opened from now on into a new file in `dir`
+pub fn record_to(dir: PathBuf) {
+    RECORD_DIR.get_or_init(|| dir);
+}
+
+pub fn record_dir() -> Option<&'static Path> {
+    RECORD_DIR.get().map(|dir| dir.as_path())
+}
+
+fn unix_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_millis())
+        .unwrap_or(0)
+}
+
+/// A log of everything sent to and received from a port.
+///
+/// ```text
+/// # /dev/ttyUSB0 115200 1760760000123
+/// 0 > at+version\r\n
+/// 12 < OK V3.0.0.14.H\r\n
+/// ```
+///
+/// Each line is the milliseconds since the port was opened, `>` for sent or
+/// `<` for received, and the bytes escaped by `escape_string`.
+pub struct Transcript {
+    file: File,
+    start: Instant,
+}
+
+impl Transcript {
+    /// Create `<dir>/<port>-<unix millis>.log`
+    pub fn create(dir: &Path, path: &str, baud: u32) -> io::Result<Transcript> {
+        fs::create_dir_all(dir)?;
+        let now = unix_millis();
+        let port: String = path
+            .rsplit(['/', '\\'])
+            .next()
+            .unwrap_or(path)
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(format!("{}-{}.log", port, now)))?;
+        let mut transcript = Transcript {
+            file,
+            start: Instant::now(),
+        };
+        writeln!(transcript.file, "# {} {} {}", path, baud, now)?;
+        Ok(transcript)
+    }
+
+    pub fn sent(&mut self, data: &str) {
+        self.write('>', data)
+    }
+
+    pub fn received(&mut self, data: &str) {
+        self.write('<', data)
+    }
+
+    fn write(&mut self, direction: char, data: &str) {
+        if data.is_empty() {
+            return;
+        }
+        let elapsed = self.start.elapsed().as_millis();
+        let line = format!(
+            "{} {} {}\n",
+            elapsed,
+            direction,
+            escape_string(data.to_string())
+        );
+        // a broken transcript must not break the command
+        if let Err(e) = self.file.write_all(line.as_bytes()) {
+            warn!("Failed to write transcript: {}", e);
+        }
+    }
+}
+
+enum Entry {
+    Sent(Vec<u8>),
+    Received(Vec<u8>),
+}
+
+/// A fake serial port playing back a `Transcript`.
+///
+/// The received bytes are handed out in order, but only once everything
+/// recorded before them has been written, so the module appears to answer
+/// each command as it did when recording. Writes that differ from the
+/// recording are logged and otherwise ignored. The timing is not replayed.
+pub struct ReplayPort {
+    name: String,
+    entries: VecDeque<Entry>,
+    baud: u32,
+    timeout: Duration,
+}
+
+impl ReplayPort {
+    pub fn open(file: &str, baud: u32, timeout: Duration) -> io::Result<ReplayPort> {
+        let content = fs::read_to_string(file)?;
+        let mut entries = VecDeque::new();
+        for (n, line) in content.lines().enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.splitn(3, ' ');
+            let data = match (fields.next(), fields.next(), fields.next()) {
+                (Some(_), Some(">"), Some(data)) => Entry::Sent(unescape_string(data).into_bytes()),
+                (Some(_), Some("<"), Some(data)) => {
+                    Entry::Received(unescape_string(data).into_bytes())
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{}:{}: not a transcript line", file, n + 1),
+                    ))
+                }
+            };
+            entries.push_back(data);
+        }
+        Ok(ReplayPort {
+            name: file.to_string(),
+            entries,
+            baud,
+            timeout,
+        })
+    }
+}
+
+impl io::Read for ReplayPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.entries.front_mut() {
+            Some(Entry::Received(data)) => {
+                let n = buf.len().min(data.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                data.drain(..n);
+                if data.is_empty() {
+                    self.entries.pop_front();
+                }
+                Ok(n)
+            }
+            // waiting for a command, or the transcript is over
+            _ => {
+                thread::sleep(self.timeout);
+                Err(io::ErrorKind::TimedOut.into())
+            }
+        }
+    }
+}
+
+impl io::Write for ReplayPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let expected = match self.entries.front_mut() {
+                Some(Entry::Sent(expected)) => expected,
+                _ => {
+                    warn!(
+                        "Replay: unexpected write {}",
+                        escape_string(String::from_utf8_lossy(rest).into_owned())
+                    );
+                    break;
+                }
+            };
+            let n = rest.len().min(expected.len());
+            if rest[..n] != expected[..n] {
+                warn!(
+                    "Replay: expected {}, written {}",
+                    escape_string(String::from_utf8_lossy(&expected[..n]).into_owned()),
+                    escape_string(String::from_utf8_lossy(&rest[..n]).into_owned())
+                );
+            }
+            expected.drain(..n);
+            if expected.is_empty() {
+                self.entries.pop_front();
+            }
+            rest = &rest[n..];
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialPort for ReplayPort {
+    fn name(&self) -> Option<String> {
+        Some(self.name.clone())
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(self.baud)
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(Parity::None)
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.baud = baud_rate;
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, _: DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, _: FlowControl) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_parity(&mut self, _: Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, _: StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        Ok(match self.entries.front() {
+            Some(Entry::Received(data)) => data.len() as u32,
+            _ => 0,
+        })
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    /// Nothing is dropped, the recording already has what the module really sent
+    fn clear(&self, _: ClearBuffer) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Err(serialport::Error::new(
+            serialport::ErrorKind::Unknown,
+            "a replay port can't be cloned",
+        ))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,304 @@
+use log::debug;
+use serialport::{ClearBuffer, SerialPort};
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// A byte stream to a module. `AtSession` only needs reads with a timeout,
+/// so the module can sit behind a local serial port or a network server like ser2net.
+pub trait Transport: Read + Write + Send {
+    /// The timeout of a single read
+    fn timeout(&self) -> Duration;
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()>;
+    /// Drop whatever was received but not read yet
+    fn clear(&mut self) -> io::Result<()>;
+}
+
+impl Transport for Box<dyn SerialPort> {
+    fn timeout(&self) -> Duration {
+        SerialPort::timeout(&**self)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        Ok(SerialPort::set_timeout(&mut **self, timeout)?)
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        Ok(SerialPort::clear(&**self, ClearBuffer::All)?)
+    }
+}
+
+fn connect(addr: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("can't resolve {}", addr));
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => {
+                stream.set_nodelay(true)?;
+                stream.set_read_timeout(Some(timeout))?;
+                return Ok(stream);
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// Unix reports a read timeout as `WouldBlock`, Windows as `TimedOut`
+fn read_timed_out(e: io::Error) -> io::Error {
+    match e.kind() {
+        io::ErrorKind::WouldBlock => io::ErrorKind::TimedOut.into(),
+        _ => e,
+    }
+}
+
+/// Drop what is in the receive buffer of the socket
+fn drain(stream: &mut TcpStream) -> io::Result<()> {
+    stream.set_nonblocking(true)?;
+    let mut buf = [0; 256];
+    let result = loop {
+        match stream.read(&mut buf) {
+            Ok(0) => break Ok(()),
+            Ok(_) => continue,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+    stream.set_nonblocking(false)?;
+    result
+}
+
+/// The raw bytes of the serial port over TCP, e.g. a ser2net `raw` port.
+/// The baud rate is whatever the server is configured with.
+pub struct TcpTransport {
+    stream: TcpStream,
+    timeout: Duration,
+}
+
+impl TcpTransport {
+    pub fn connect(addr: &str, timeout: Duration) -> io::Result<TcpTransport> {
+        Ok(TcpTransport {
+            stream: connect(addr, timeout)?,
+            timeout,
+        })
+    }
+}
+
+impl Read for TcpTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf).map_err(read_timed_out)
+    }
+}
+
+impl Write for TcpTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Transport for TcpTransport {
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.stream.set_read_timeout(Some(timeout))?;
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        drain(&mut self.stream)
+    }
+}
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const BINARY: u8 = 0;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+const COM_PORT_OPTION: u8 = 44;
+const SET_BAUDRATE: u8 = 1;
+const SET_DATASIZE: u8 = 2;
+const SET_PARITY: u8 = 3;
+const SET_STOPSIZE: u8 = 4;
+const PARITY_NONE: u8 = 1;
+const STOPSIZE_ONE: u8 = 1;
+
+/// Where the telnet parser is in the received stream
+#[derive(Clone, Copy)]
+enum Telnet {
+    Data,
+    Iac,
+    /// After `IAC WILL/WONT/DO/DONT`
+    Option(u8),
+    /// Inside `IAC SB ... IAC SE`
+    Sub,
+    SubIac,
+}
+
+/// A serial port over telnet with the COM port control option (RFC 2217),
+/// e.g. a ser2net `telnet` port with `remctl`. Only 8N1 at the given baud rate is supported.
+pub struct Rfc2217Transport {
+    stream: TcpStream,
+    timeout: Duration,
+    state: Telnet,
+}
+
+impl Rfc2217Transport {
+    pub fn connect(addr: &str, baud: u32, timeout: Duration) -> io::Result<Rfc2217Transport> {
+        let mut stream = connect(addr, timeout)?;
+        let mut negotiation = vec![
+            IAC,
+            WILL,
+            BINARY,
+            IAC,
+            DO,
+            BINARY,
+            IAC,
+            WILL,
+            SUPPRESS_GO_AHEAD,
+            IAC,
+            DO,
+            SUPPRESS_GO_AHEAD,
+            IAC,
+            WILL,
+            COM_PORT_OPTION,
+        ];
+        let mut sub = |command: u8, value: &[u8]| {
+            negotiation.extend([IAC, SB, COM_PORT_OPTION, command]);
+            for byte in value {
+                negotiation.push(*byte);
+                if *byte == IAC {
+                    negotiation.push(IAC);
+                }
+            }
+            negotiation.extend([IAC, SE]);
+        };
+        sub(SET_BAUDRATE, &baud.to_be_bytes());
+        sub(SET_DATASIZE, &[8]);
+        sub(SET_PARITY, &[PARITY_NONE]);
+        sub(SET_STOPSIZE, &[STOPSIZE_ONE]);
+        stream.write_all(&negotiation)?;
+        Ok(Rfc2217Transport {
+            stream,
+            timeout,
+            state: Telnet::Data,
+        })
+    }
+
+    /// Refuse every option we did not ask for
+    fn answer(&mut self, command: u8, option: u8) -> io::Result<()> {
+        let wanted = matches!(option, BINARY | SUPPRESS_GO_AHEAD)
+            || (command == DO && option == COM_PORT_OPTION);
+        let reply = match command {
+            DO if !wanted => WONT,
+            WILL if !wanted => DONT,
+            _ => return Ok(()),
+        };
+        debug!("Telnet: refuse option {}", option);
+        self.stream.write_all(&[IAC, reply, option])
+    }
+
+    /// Strip the telnet commands from `raw`, leaving the data in `buf`
+    fn filter(&mut self, raw: &[u8], buf: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+        for byte in raw {
+            self.state = match (self.state, *byte) {
+                (Telnet::Data, IAC) => Telnet::Iac,
+                (Telnet::Data, byte) | (Telnet::Iac, byte @ IAC) => {
+                    buf[n] = byte;
+                    n += 1;
+                    Telnet::Data
+                }
+                (Telnet::Iac, command @ (WILL | WONT | DO | DONT)) => Telnet::Option(command),
+                (Telnet::Iac, SB) => Telnet::Sub,
+                // the other two byte commands carry nothing we need
+                (Telnet::Iac, _) => Telnet::Data,
+                (Telnet::Option(command), option) => {
+                    self.answer(command, option)?;
+                    Telnet::Data
+                }
+                (Telnet::Sub, IAC) => Telnet::SubIac,
+                (Telnet::Sub, _) => Telnet::Sub,
+                (Telnet::SubIac, SE) => Telnet::Data,
+                (Telnet::SubIac, _) => Telnet::Sub,
+            };
+        }
+        Ok(n)
+    }
+}
+
+impl Read for Rfc2217Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // never more than `buf` can hold, as every byte may be data
+        let mut raw = vec![0; buf.len()];
+        loop {
+            let len = self.stream.read(&mut raw).map_err(read_timed_out)?;
+            if len == 0 {
+                return Ok(0);
+            }
+            let n = self.filter(&raw[..len], buf)?;
+            // a read of only telnet commands is not the end of the stream
+            if n > 0 {
+                return Ok(n);
+            }
+        }
+    }
+}
+
+impl Write for Rfc2217Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut escaped = Vec::with_capacity(buf.len());
+        for byte in buf {
+            escaped.push(*byte);
+            if *byte == IAC {
+                escaped.push(IAC);
+            }
+        }
+        self.stream.write_all(&escaped)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Transport for Rfc2217Transport {
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.stream.set_read_timeout(Some(timeout))?;
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    /// The data is dropped, but the telnet commands are still answered
+    fn clear(&mut self) -> io::Result<()> {
+        self.stream.set_nonblocking(true)?;
+        let mut buf = [0; 256];
+        let result = loop {
+            match self.read(&mut buf) {
+                Ok(0) => break Ok(()),
+                Ok(_) => continue,
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        self.stream.set_nonblocking(false)?;
+        result
+    }
+}
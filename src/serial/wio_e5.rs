@@ -0,0 +1,418 @@
+use super::modem::{listen_lines, on_off};
+use super::{AtError, AtSession, Downlink, LoraModem, LoraStatus, SerialError};
+use crate::lora::{Class, JoinMode, Region};
+use log::{debug, info};
+use std::time::{Duration, Instant};
+
+/// How long the line must be quiet before a response without `Done` is
+/// considered complete, e.g. the three lines of `AT+ID`
+const TRAILING_GAP: Duration = Duration::from_millis(200);
+/// How long `send` waits for `+MSGHEX: Done`, including the RX windows
+const SEND_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Seeed LoRa-E5 / Wio-E5.
+///
+/// Every response line repeats the command, e.g. `AT+ID=DevEui,"..."` is
+/// answered by `+ID: DevEui, 2C:F7:F1:20:32:30:4B:41`. Long running commands
+/// (`AT+JOIN`, `AT+MSGHEX`) print several lines and finish with `+<CMD>: Done`.
+/// There is no confirm setting, confirmed uplinks are sent with `AT+CMSGHEX`.
+pub struct WioE5 {
+    session: AtSession,
+    /// The keys echoed by the module when they were set. They can't be queried.
+    app_key: Option<String>,
+    nwks_key: Option<String>,
+    apps_key: Option<String>,
+}
+
+impl WioE5 {
+    pub fn new(session: AtSession) -> WioE5 {
+        WioE5 {
+            session,
+            app_key: None,
+            nwks_key: None,
+            apps_key: None,
+        }
+    }
+
+    /// `AT+PORT=<port>` then `AT+<cmd>="<hex>"`
+    fn send_hex(&mut self, cmd: &str, port: u8, payload: &str) -> Result<(), SerialError> {
+        self.set("PORT", &port.to_string())?;
+        let args = format!("\"{}\"", payload);
+        let body = self.send_read(cmd, Some(&args), SEND_TIMEOUT, true)?;
+        for line in body {
+            if let Some(e) = parse_send_error(&line) {
+                return Err(SerialError::Device(e));
+            }
+        }
+        Ok(())
+    }
+
+    /// Send `AT+<cmd>` or `AT+<cmd>=<args>` and collect the payloads of the
+    /// `+<CMD>: ` lines. With `until_done` the response ends at `+<CMD>: Done`,
+    /// otherwise when the line goes quiet after the first payload.
+    fn send_read(
+        &mut self,
+        cmd: &str,
+        args: Option<&str>,
+        timeout: Duration,
+        until_done: bool,
+    ) -> Result<Vec<String>, SerialError> {
+        let command = match args {
+            Some(args) => format!("AT+{}={}\r\n", cmd, args),
+            None => format!("AT+{}\r\n", cmd),
+        };
+        self.session.write_command(&command)?;
+        let mut deadline = Instant::now() + timeout;
+        let mut body: Vec<String> = Vec::new();
+        loop {
+            let line = match self.session.read_line(deadline)? {
+                Some(line) => line,
+                None if !until_done && !body.is_empty() => return Ok(body),
+                None => return Err(SerialError::Timeout),
+            };
+            let (name, payload) = match split_response(&line) {
+                Some((name, payload)) if name.eq_ignore_ascii_case(cmd) => (name, payload),
+                _ => {
+                    if !line.trim().is_empty() {
+                        info!("Event: {}", line.trim());
+                    }
+                    continue;
+                }
+            };
+            if let Some(e) = parse_error(payload) {
+                return Err(SerialError::Device(e));
+            }
+            debug!("{}: {}", name, payload);
+            if until_done && payload == "Done" {
+                return Ok(body);
+            }
+            body.push(payload.to_string());
+            if !until_done {
+                deadline = Instant::now() + TRAILING_GAP;
+            }
+        }
+    }
+
+    /// `AT+ID=<name>,"<value>"`
+    fn set_id(&mut self, name: &str, value: &str) -> Result<(), SerialError> {
+        let timeout = self.session.timeout();
+        let args = format!("{},\"{}\"", name, value);
+        self.send_read("ID", Some(&args), timeout, false)?;
+        Ok(())
+    }
+
+    /// `AT+KEY=<name>,"<value>"`, returns the key echoed by the module
+    fn set_key(&mut self, name: &str, value: &str) -> Result<Option<String>, SerialError> {
+        let timeout = self.session.timeout();
+        let args = format!("{},\"{}\"", name, value);
+        let body = self.send_read("KEY", Some(&args), timeout, false)?;
+        Ok(body.iter().find_map(|line| key_echo(line, name)))
+    }
+
+    /// `AT+<cmd>=<args>` for the commands answering with a single line
+    fn set(&mut self, cmd: &str, args: &str) -> Result<(), SerialError> {
+        let timeout = self.session.timeout();
+        self.send_read(cmd, Some(args), timeout, false)?;
+        Ok(())
+    }
+}
+
+impl LoraModem for WioE5 {
+    /// `AT+VER` answers `+VER: 4.0.11`
+    fn version(&mut self) -> Result<String, SerialError> {
+        let timeout = self.session.timeout();
+        let body = self.send_read("VER", None, timeout, false)?;
+        Ok(body.join("\n"))
+    }
+
+    fn set_dev_eui(&mut self, dev_eui: &str) -> Result<(), SerialError> {
+        self.set_id("DevEui", dev_eui)
+    }
+
+    fn set_app_eui(&mut self, app_eui: &str) -> Result<(), SerialError> {
+        self.set_id("AppEui", app_eui)
+    }
+
+    fn set_app_key(&mut self, app_key: &str) -> Result<(), SerialError> {
+        self.app_key = self.set_key("APPKEY", app_key)?;
+        Ok(())
+    }
+
+    /// `AT+ID` lists DevAddr, DevEui and AppEui. The module never prints the
+    /// AppKey back, so the one it echoed when it was set is reported instead.
+    fn status(&mut self) -> Result<LoraStatus, SerialError> {
+        let timeout = self.session.timeout();
+        let body = self.send_read("ID", None, timeout, false)?;
+        let find = |name: &str| body.iter().find_map(|line| field(line, name));
+        Ok(LoraStatus {
+            dev_eui: find("DevEui"),
+            app_eui: find("AppEui"),
+            app_key: self.app_key.clone(),
+            dev_addr: find("DevAddr"),
+            nwks_key: self.nwks_key.clone(),
+            apps_key: self.apps_key.clone(),
+        })
+    }
+
+    /// `AT+JOIN` prints `+JOIN: Network joined` or `+JOIN: Join failed`,
+    /// then `+JOIN: Done`
+    fn join(&mut self, timeout: Duration) -> Result<(), SerialError> {
+        let body = self.send_read("JOIN", None, timeout, true)?;
+        let joined = body
+            .iter()
+            .any(|line| line.starts_with("Network joined") || line.starts_with("Joined already"));
+        if joined {
+            Ok(())
+        } else {
+            Err(SerialError::Device(AtError::JoinFailed))
+        }
+    }
+
+    fn send(&mut self, port: u8, payload: &str) -> Result<(), SerialError> {
+        self.send_hex("MSGHEX", port, payload)
+    }
+
+    fn send_confirmed(&mut self, port: u8, payload: &str) -> Result<(), SerialError> {
+        self.send_hex("CMSGHEX", port, payload)
+    }
+
+    /// Downlinks are printed as `+MSG: PORT: 1; RX: "AABB"` followed by
+    /// `+MSG: RXWIN1, RSSI -106, SNR 4.0`, see `RxParser`. The command in front
+    /// may also be `MSGHEX`, `CMSG` or `CMSGHEX`.
+    fn listen(&mut self, on_downlink: &mut dyn FnMut(Downlink)) -> Result<(), SerialError> {
+        let mut parser = RxParser::default();
+        listen_lines(
+            &mut self.session,
+            &mut |line| parser.line(line),
+            on_downlink,
+        )
+    }
+
+    fn set_join_mode(&mut self, mode: JoinMode) -> Result<(), SerialError> {
+        let value = match mode {
+            JoinMode::Otaa => "LWOTAA",
+            JoinMode::Abp => "LWABP",
+        };
+        self.set("MODE", value)
+    }
+
+    /// The band plan is selected by `AT+DR=<region>`
+    fn set_region(&mut self, region: Region) -> Result<(), SerialError> {
+        self.set("DR", region.as_str())
+    }
+
+    fn set_class(&mut self, class: Class) -> Result<(), SerialError> {
+        let value = match class {
+            Class::A => "A",
+            Class::B => "B",
+            Class::C => "C",
+        };
+        self.set("CLASS", value)
+    }
+
+    fn set_dr(&mut self, dr: u8) -> Result<(), SerialError> {
+        self.set("DR", &dr.to_string())
+    }
+
+    fn set_adr(&mut self, adr: bool) -> Result<(), SerialError> {
+        self.set("ADR", &on_off(adr).to_ascii_uppercase())
+    }
+
+    /// Wio-E5 takes the TX power in dBm
+    fn set_tx_power(&mut self, tx_power: u8) -> Result<(), SerialError> {
+        self.set("POWER", &tx_power.to_string())
+    }
+
+    fn set_dev_addr(&mut self, dev_addr: &str) -> Result<(), SerialError> {
+        self.set_id("DevAddr", dev_addr)
+    }
+
+    fn set_nwks_key(&mut self, nwks_key: &str) -> Result<(), SerialError> {
+        self.nwks_key = self.set_key("NWKSKEY", nwks_key)?;
+        Ok(())
+    }
+
+    fn set_apps_key(&mut self, apps_key: &str) -> Result<(), SerialError> {
+        self.apps_key = self.set_key("APPSKEY", apps_key)?;
+        Ok(())
+    }
+}
+
+/// `+ID: DevEui, 2C:F7:F1:20:32:30:4B:41` into `("ID", "DevEui, 2C:F7:F1:20:32:30:4B:41")`
+fn split_response(line: &str) -> Option<(&str, &str)> {
+    let (name, payload) = line.trim().strip_prefix('+')?.split_once(':')?;
+    Some((name.trim(), payload.trim()))
+}
+
+/// The value of `<name>, <value>` with the colons between the octets removed
+fn field(line: &str, name: &str) -> Option<String> {
+    let (key, value) = line.split_once(',')?;
+    if !key.trim().eq_ignore_ascii_case(name) {
+        return None;
+    }
+    Some(value.trim().replace(':', ""))
+}
+
+/// The key of `<name> <value>`, as echoed by `AT+KEY`, e.g. `APPKEY 2B7E151628AED2A6ABF7158809CF4F3C`
+fn key_echo(line: &str, name: &str) -> Option<String> {
+    let mut parts = line.split_whitespace();
+    if !parts.next()?.eq_ignore_ascii_case(name) {
+        return None;
+    }
+    Some(parts.next()?.to_string())
+}
+
+/// `ERROR(-1)` and the other error codes of Wio-E5
+fn parse_error(payload: &str) -> Option<AtError> {
+    let code = payload
+        .strip_prefix("ERROR(")?
+        .trim_end_matches(')')
+        .parse::<i32>()
+        .ok()?;
+    let e = match code {
+        -1 | -11 | -20 | -21 => AtError::InvalidParameter,
+        -10 | -12 => AtError::UnsupportedCommand,
+        _ => AtError::Unknown(code.unsigned_abs() as u16),
+    };
+    Some(e)
+}
+
+/// The lines of `AT+MSGHEX` that mean the message was not sent
+fn parse_send_error(payload: &str) -> Option<AtError> {
+    let e = if payload.starts_with("Please join network first") {
+        AtError::NotJoined
+    } else if payload.starts_with("No free channel") {
+        AtError::NoAvailableChannel
+    } else if payload.starts_with("Length error") {
+        AtError::InvalidPayloadSize
+    } else if payload.starts_with("DR error") {
+        AtError::InvalidDataRate
+    } else if payload.contains("busy") {
+        AtError::Busy
+    } else {
+        return None;
+    };
+    Some(e)
+}
+
+/// Pairs the data of a downlink with the window printed after it. The data is
+/// held until the `RXWIN` line, or `Done` when there is none (RSSI and SNR
+/// are then 0). A window without data, e.g. an ACK, is dropped.
+#[derive(Default)]
+struct RxParser {
+    pending: Option<(u8, String)>,
+}
+
+impl RxParser {
+    fn line(&mut self, line: &str) -> Option<Downlink> {
+        let (_, payload) = split_response(line)?;
+        if let Some((port, data)) = parse_rx(payload) {
+            // a second data line before any window, flush the first one
+            return self
+                .pending
+                .replace((port, data))
+                .map(|(port, data)| downlink(port, data, 0, 0));
+        }
+        if let Some((rssi, snr)) = parse_rx_window(payload) {
+            let (port, data) = self.pending.take()?;
+            return Some(downlink(port, data, rssi, snr));
+        }
+        if payload == "Done" {
+            let (port, data) = self.pending.take()?;
+            return Some(downlink(port, data, 0, 0));
+        }
+        None
+    }
+}
+
+fn downlink(port: u8, data: String, rssi: i16, snr: i16) -> Downlink {
+    Downlink {
+        port,
+        rssi,
+        snr,
+        len: data.len() / 2,
+        data,
+    }
+}
+
+/// `RXWIN1, RSSI -106, SNR 4.0`
+fn parse_rx_window(payload: &str) -> Option<(i16, i16)> {
+    if !payload.starts_with("RXWIN") {
+        return None;
+    }
+    let mut rssi = None;
+    let mut snr = None;
+    for part in payload.split(',') {
+        let part = part.trim();
+        if let Some(value) = part.strip_prefix("RSSI ") {
+            rssi = value.trim().parse::<i16>().ok();
+        } else if let Some(value) = part.strip_prefix("SNR ") {
+            snr = value
+                .trim()
+                .parse::<f32>()
+                .ok()
+                .map(|snr| snr.round() as i16);
+        }
+    }
+    Some((rssi?, snr?))
+}
+
+/// `PORT: 1; RX: "AABB"`
+fn parse_rx(payload: &str) -> Option<(u8, String)> {
+    let (port, rx) = payload.split_once(';')?;
+    let port = port.trim().strip_prefix("PORT:")?.trim().parse().ok()?;
+    let data = rx.trim().strip_prefix("RX:")?.trim().trim_matches('"');
+    Some((port, data.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_echo_is_space_separated() {
+        let (_, payload) = split_response("+KEY: APPKEY 2B7E151628AED2A6ABF7158809CF4F3C").unwrap();
+        assert_eq!(
+            key_echo(payload, "APPKEY").as_deref(),
+            Some("2B7E151628AED2A6ABF7158809CF4F3C")
+        );
+        assert_eq!(key_echo(payload, "NWKSKEY"), None);
+        assert_eq!(key_echo("APPKEY", "APPKEY"), None);
+    }
+
+    #[test]
+    fn id_field_is_comma_separated() {
+        let (_, payload) = split_response("+ID: DevEui, 2C:F7:F1:20:32:30:4B:41").unwrap();
+        assert_eq!(
+            field(payload, "DevEui").as_deref(),
+            Some("2CF7F12032304B41")
+        );
+        assert_eq!(field(payload, "AppEui"), None);
+    }
+
+    #[test]
+    fn rx_window_follows_the_data() {
+        let mut parser = RxParser::default();
+        assert_eq!(parser.line("+MSG: PORT: 1; RX: \"AABB\""), None);
+        assert_eq!(
+            parser.line("+MSG: RXWIN1, RSSI -106, SNR 4.0"),
+            Some(downlink(1, "AABB".to_string(), -106, 4))
+        );
+        assert_eq!(parser.line("+MSG: Done"), None);
+        // an ACK only, its window is not carried over
+        assert_eq!(parser.line("+CMSGHEX: ACK Received"), None);
+        assert_eq!(parser.line("+CMSGHEX: RXWIN2, RSSI -90, SNR 7.0"), None);
+        assert_eq!(parser.line("+CMSGHEX: Done"), None);
+        assert_eq!(parser.line("+MSGHEX: PORT: 2; RX: \"CC\""), None);
+        assert_eq!(
+            parser.line("+MSGHEX: RXWIN1, RSSI -110, SNR -3.0"),
+            Some(downlink(2, "CC".to_string(), -110, -3))
+        );
+        // no window at all
+        assert_eq!(parser.line("+MSG: PORT: 3; RX: \"DD\""), None);
+        assert_eq!(
+            parser.line("+MSG: Done"),
+            Some(downlink(3, "DD".to_string(), 0, 0))
+        );
+    }
+}
@@ -0,0 +1,529 @@
+use crate::lora::{Class, JoinMode, Region};
+use crate::serial::AtError;
+use crate::utils::escape_string;
+use crate::utils::gen_hex::is_hex;
+use clap::ValueEnum;
+use log::{debug, error, info};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// The largest payload accepted by `at+send`, whatever the data rate
+const MAX_PAYLOAD: usize = 242;
+
+/// How the simulated module misbehaves
+#[derive(Debug, Clone, Default)]
+pub struct SimOptions {
+    /// How long `at+join` takes
+    pub join_delay: Duration,
+    /// The first `join_failures` joins fail with `ERROR: 99`
+    pub join_failures: u32,
+    /// Commands starting with the prefix always fail with the code
+    pub fail: Vec<(String, u16)>,
+    /// The probability that any command fails with `ERROR: 80`
+    pub error_rate: f64,
+    /// Sent as `at+recv` after every uplink, as `<port>:<hex>`
+    pub downlink: Option<(u8, String)>,
+}
+
+/// Parse `<prefix>=<code>` of `--fail`
+pub fn parse_fail(s: &str) -> Result<(String, u16), String> {
+    let (prefix, code) = s
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected <command>=<code>, got {}", s))?;
+    let code = code
+        .parse()
+        .map_err(|e| format!("invalid error code {}: {}", code, e))?;
+    Ok((prefix.to_string(), code))
+}
+
+/// Parse `<port>:<hex>` of `--downlink`
+pub fn parse_downlink(s: &str) -> Result<(u8, String), String> {
+    let (port, data) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected <port>:<hex>, got {}", s))?;
+    let port = port
+        .parse()
+        .map_err(|e| format!("invalid port {}: {}", port, e))?;
+    if data.len() % 2 != 0 || !is_hex(data) {
+        return Err(format!("invalid hex payload {}", data));
+    }
+    Ok((port, data.to_string()))
+}
+
+/// The state of a RAK811 with the V3 AT firmware, as far as this tool uses it
+pub struct Rak811Sim {
+    opts: SimOptions,
+    dev_eui: String,
+    app_eui: String,
+    app_key: String,
+    dev_addr: String,
+    nwks_key: String,
+    apps_key: String,
+    join_mode: JoinMode,
+    region: Region,
+    class: Class,
+    confirm: bool,
+    dr: u8,
+    adr: bool,
+    tx_power: u8,
+    ch_mask: [bool; 72],
+    joined: bool,
+    joins: u32,
+    up_count: u32,
+    down_count: u32,
+    /// Lines to send later, like the result of `at+join`
+    pending: Vec<(Instant, String)>,
+}
+
+impl Rak811Sim {
+    pub fn new(opts: SimOptions) -> Rak811Sim {
+        let mut ch_mask = [false; 72];
+        ch_mask[..8].fill(true);
+        Rak811Sim {
+            opts,
+            dev_eui: "0000000000000000".into(),
+            app_eui: "0000000000000000".into(),
+            app_key: "00000000000000000000000000000000".into(),
+            dev_addr: "00000000".into(),
+            nwks_key: "00000000000000000000000000000000".into(),
+            apps_key: "00000000000000000000000000000000".into(),
+            join_mode: JoinMode::Otaa,
+            region: Region::Eu868,
+            class: Class::A,
+            confirm: false,
+            dr: 5,
+            adr: true,
+            tx_power: 0,
+            ch_mask,
+            joined: false,
+            joins: 0,
+            up_count: 0,
+            down_count: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    /// The lines whose time has come
+    pub fn due(&mut self) -> Vec<String> {
+        let now = Instant::now();
+        let (due, pending) = self.pending.drain(..).partition(|(at, _)| *at <= now);
+        self.pending = pending;
+        due.into_iter().map(|(_, line)| line).collect()
+    }
+
+    /// Answer a command, without the trailing `\r\n`
+    pub fn handle(&mut self, command: &str) -> Vec<String> {
+        if let Some((_, code)) = self
+            .opts
+            .fail
+            .iter()
+            .find(|(prefix, _)| command.starts_with(prefix.as_str()))
+        {
+            return vec![error(AtError::from_code(*code))];
+        }
+        if self.opts.error_rate > 0.0 && rand::random::<f64>() < self.opts.error_rate {
+            return vec![error(AtError::Busy)];
+        }
+        let result = if command == "at+version" {
+            Ok(vec!["OK V3.0.0.14.H".to_string()])
+        } else if command == "at+join" {
+            self.join()
+        } else if command == "at+get_config=lora:status" {
+            Ok(self.status())
+        } else if command == "at+get_config=lora:channel" {
+            Ok(vec![self.channels()])
+        } else if let Some(rest) = command.strip_prefix("at+set_config=lora:") {
+            self.set_config(rest).map(|_| vec!["OK".to_string()])
+        } else if let Some(rest) = command.strip_prefix("at+send=lora:") {
+            self.send(rest)
+        } else {
+            Err(AtError::UnsupportedCommand)
+        };
+        result.unwrap_or_else(|e| vec![error(e)])
+    }
+
+    fn set_config(&mut self, rest: &str) -> Result<(), AtError> {
+        let (key, value) = rest.split_once(':').ok_or(AtError::InvalidParameter)?;
+        let hex = |len: usize| {
+            if value.len() == len && is_hex(value) {
+                Ok(value.to_ascii_uppercase())
+            } else {
+                Err(AtError::InvalidParameter)
+            }
+        };
+        let flag = || match value {
+            "0" => Ok(false),
+            "1" => Ok(true),
+            _ => Err(AtError::InvalidParameter),
+        };
+        let number = |max: u8| match value.parse::<u8>() {
+            Ok(n) if n <= max => Ok(n),
+            _ => Err(AtError::InvalidParameter),
+        };
+        match key {
+            "dev_eui" => self.dev_eui = hex(16)?,
+            "app_eui" => self.app_eui = hex(16)?,
+            "app_key" => self.app_key = hex(32)?,
+            "dev_addr" => self.dev_addr = hex(8)?,
+            "nwks_key" => self.nwks_key = hex(32)?,
+            "apps_key" => self.apps_key = hex(32)?,
+            "join_mode" => {
+                self.join_mode = if flag()? {
+                    JoinMode::Abp
+                } else {
+                    JoinMode::Otaa
+                };
+                self.joined = false;
+            }
+            "region" => {
+                self.region =
+                    Region::from_str(value, true).map_err(|_| AtError::UnsupportedRegion)?;
+                self.joined = false;
+            }
+            "class" => {
+                self.class = match number(2)? {
+                    0 => Class::A,
+                    1 => Class::B,
+                    _ => Class::C,
+                }
+            }
+            "confirm" => self.confirm = flag()?,
+            "dr" => self.dr = number(15).map_err(|_| AtError::InvalidDataRate)?,
+            "adr" => self.adr = flag()?,
+            "tx_power" => self.tx_power = number(10)?,
+            "ch_mask" => {
+                let (channel, enabled) = value.split_once(':').ok_or(AtError::InvalidParameter)?;
+                let channel = channel
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|channel| *channel < self.ch_mask.len())
+                    .ok_or(AtError::InvalidParameter)?;
+                self.ch_mask[channel] = match enabled {
+                    "0" => false,
+                    "1" => true,
+                    _ => return Err(AtError::InvalidParameter),
+                };
+            }
+            _ => return Err(AtError::InvalidParameter),
+        }
+        Ok(())
+    }
+
+    /// `OK` right away, the result once `join_delay` is over
+    fn join(&mut self) -> Result<Vec<String>, AtError> {
+        if self.join_mode == JoinMode::Abp {
+            self.joined = true;
+            return Ok(vec!["OK Join Success".to_string()]);
+        }
+        let zero = |key: &str| key.chars().all(|c| c == '0');
+        if zero(&self.dev_eui) || zero(&self.app_key) {
+            return Err(AtError::InvalidLoraParameter);
+        }
+        self.joins += 1;
+        let result = if self.joins <= self.opts.join_failures {
+            error(AtError::JoinFailed)
+        } else {
+            self.joined = true;
+            "OK Join Success".to_string()
+        };
+        self.pending
+            .push((Instant::now() + self.opts.join_delay, result));
+        Ok(vec!["OK".to_string()])
+    }
+
+    fn send(&mut self, rest: &str) -> Result<Vec<String>, AtError> {
+        let (port, data) = rest.split_once(':').ok_or(AtError::InvalidParameter)?;
+        port.parse::<u8>()
+            .ok()
+            .filter(|port| (1..=223).contains(port))
+            .ok_or(AtError::InvalidParameter)?;
+        if data.len() % 2 != 0 || !is_hex(data) {
+            return Err(AtError::InvalidParameter);
+        }
+        if !self.joined {
+            return Err(AtError::NotJoined);
+        }
+        if data.len() / 2 > MAX_PAYLOAD {
+            return Err(AtError::PacketTooLong);
+        }
+        self.up_count += 1;
+        let mut lines = vec!["OK".to_string()];
+        if let Some((port, data)) = &self.opts.downlink {
+            self.down_count += 1;
+            lines.push(format!(
+                "at+recv={},-45,9,{}:{}",
+                port,
+                data.len() / 2,
+                data
+            ));
+        } else if self.confirm {
+            // the ack of a confirmed uplink
+            lines.push("at+recv=0,-45,9,0".to_string());
+        }
+        Ok(lines)
+    }
+
+    /// `OK <first line>` and the rest, like the real module
+    fn status(&self) -> Vec<String> {
+        let on_off = |b: bool| if b { "true" } else { "false" };
+        vec![
+            "OK Work Mode: LoRaWAN".to_string(),
+            format!("Region: {}", self.region.as_str()),
+            format!(
+                "Join_mode: {}",
+                match self.join_mode {
+                    JoinMode::Otaa => "OTAA",
+                    JoinMode::Abp => "ABP",
+                }
+            ),
+            format!("DevEui: {}", self.dev_eui),
+            format!("AppEui: {}", self.app_eui),
+            format!("AppKey: {}", self.app_key),
+            format!("DevAddr: {}", self.dev_addr),
+            format!("AppsKey: {}", self.apps_key),
+            format!("NwksKey: {}", self.nwks_key),
+            format!("Class: {:?}", self.class),
+            format!("Joined Network:{}", on_off(self.joined)),
+            format!(
+                "IsConfirm: {}",
+                if self.confirm { "confirm" } else { "unconfirm" }
+            ),
+            format!("AdrEnable: {}", on_off(self.adr)),
+            format!("Current Datarate: {}", self.dr),
+            format!("ChannelsTxPower: {}", self.tx_power),
+            format!("UpLinkCounter: {}", self.up_count),
+            format!("DownLinkCounter: {}", self.down_count),
+        ]
+    }
+
+    /// `*<n>,on,<freq>,0,5` for the enabled channels, `<n>,off,<freq>,0,5` for the others
+    fn channels(&self) -> String {
+        let (count, base) = match self.region {
+            Region::Us915 | Region::Au915 => (72, 902_300_000),
+            Region::Cn470 => (72, 470_300_000),
+            _ => (16, 868_100_000),
+        };
+        let channels: Vec<String> = (0..count)
+            .map(|n| {
+                let enabled = self.ch_mask[n];
+                format!(
+                    "{}{},{},{},0,5",
+                    if enabled { "*" } else { "" },
+                    n,
+                    if enabled { "on" } else { "off" },
+                    base + 200_000 * n as u32
+                )
+            })
+            .collect();
+        format!("OK {}", channels.join("; "))
+    }
+}
+
+fn error(e: AtError) -> String {
+    format!("ERROR: {}", e.code())
+}
+
+/// Serve the simulated module on `port` until it is closed
+fn serve<P: Read + Write>(port: &mut P, sim: &mut Rak811Sim) -> io::Result<()> {
+    let mut line = Vec::new();
+    let mut buf = [0; 256];
+    loop {
+        for reply in sim.due() {
+            debug!("Reply: {}", reply);
+            port.write_all(format!("{}\r\n", reply).as_bytes())?;
+        }
+        let n = match port.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e),
+        };
+        for byte in &buf[..n] {
+            if *byte != b'\r' && *byte != b'\n' {
+                line.push(*byte);
+                continue;
+            }
+            if line.is_empty() {
+                continue;
+            }
+            let command = String::from_utf8_lossy(&line).trim().to_string();
+            line.clear();
+            debug!("Command: {}", escape_string(command.clone()));
+            for reply in sim.handle(&command) {
+                debug!("Reply: {}", reply);
+                port.write_all(format!("{}\r\n", reply).as_bytes())?;
+            }
+        }
+    }
+}
+
+/// Create a pseudo-terminal and serve a simulated RAK811 on it until interrupted.
+/// `link` is a symlink to the terminal, so it can be found at a fixed path.
+#[cfg(unix)]
+pub fn handle_sim(opts: SimOptions, link: Option<PathBuf>) {
+    use serialport::{SerialPort, TTYPort};
+    // the slave end is kept open, so the master survives clients closing the port
+    let (mut master, slave) = TTYPort::pair().expect("Failed to create a pseudo-terminal");
+    let path = slave.name().expect("The pseudo-terminal has no name");
+    if let Some(link) = &link {
+        let _ = std::fs::remove_file(link);
+        if let Err(e) = std::os::unix::fs::symlink(&path, link) {
+            error!("Failed to link {:?} to {}: {}", link, path, e);
+            std::process::exit(1);
+        }
+    }
+    info!(
+        "Simulated RAK811 on {}{}. Press Ctrl-C to stop.",
+        path,
+        link.as_ref()
+            .map(|link| format!(" ({})", link.display()))
+            .unwrap_or_default()
+    );
+    let mut sim = Rak811Sim::new(opts);
+    if let Err(e) = serve(&mut master, &mut sim) {
+        error!("{}", e);
+    }
+    drop(slave);
+}
+
+#[cfg(not(unix))]
+pub fn handle_sim(_: SimOptions, _: Option<PathBuf>) {
+    error!("The simulator needs a pseudo-terminal, which is only available on Unix");
+    std::process::exit(1);
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::lora::Class;
+    use crate::payload::Encoding;
+    use crate::provision::{self, ProvisionOptions};
+    use crate::serial::at::{self, AtCommands, SetCommands};
+    use crate::serial::ModuleKind;
+    use crate::user_config::Config;
+    use serialport::{SerialPort, TTYPort};
+    use std::io::BufRead;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Serve a simulated RAK811 in the background and return the path of its terminal
+    fn spawn_sim(opts: SimOptions) -> String {
+        let (mut master, slave) = TTYPort::pair().unwrap();
+        let path = slave.name().unwrap();
+        thread::spawn(move || {
+            let _slave = slave;
+            let _ = serve(&mut master, &mut Rak811Sim::new(opts));
+        });
+        path
+    }
+
+    /// Answer every request with `{}` and return the URL of the API
+    fn spawn_api() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+                let mut len = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let lower = line.to_ascii_lowercase();
+                    if let Some(value) = lower.strip_prefix("content-length:") {
+                        len = value.trim().parse().unwrap();
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut body = vec![0; len];
+                reader.read_exact(&mut body).unwrap();
+                stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                          Content-Length: 2\r\nConnection: close\r\n\r\n{}",
+                    )
+                    .unwrap();
+            }
+        });
+        url
+    }
+
+    fn run(path: &String, command: AtCommands) -> Result<(), Box<dyn std::error::Error>> {
+        at::handle_at_commands(path, &Some(115200), &Some(ModuleKind::Rak811), &command)
+    }
+
+    fn join() -> AtCommands {
+        AtCommands::Join {
+            attempts: 1,
+            backoff: 0,
+            timeout: 5,
+            mode: None,
+        }
+    }
+
+    #[test]
+    fn at_commands_join_and_send() {
+        let path = spawn_sim(SimOptions::default());
+        // the keys of a fresh module are all zero
+        assert!(run(&path, join()).is_err());
+        let dev_eui = SetCommands::DevEui {
+            dev_eui: String::new(),
+        };
+        run(&path, AtCommands::Set { command: dev_eui }).unwrap();
+        let app_key = SetCommands::AppKey {
+            app_key: String::new(),
+        };
+        run(&path, AtCommands::Set { command: app_key }).unwrap();
+        run(&path, join()).unwrap();
+        let send = AtCommands::Send {
+            msg: Some("Hi".to_string()),
+            chn: 1,
+            confirm: false,
+            raw: false,
+            file: None,
+            encoding: Encoding::Text,
+            lpp: Vec::new(),
+        };
+        run(&path, send).unwrap();
+    }
+
+    #[test]
+    fn at_command_rejected_by_the_module() {
+        let path = spawn_sim(SimOptions {
+            fail: vec![("at+set_config=lora:class".to_string(), 86)],
+            ..Default::default()
+        });
+        let class = SetCommands::Class { class: Class::C };
+        assert!(run(&path, AtCommands::Set { command: class }).is_err());
+    }
+
+    #[test]
+    fn provision_registers_the_written_keys() {
+        let path = spawn_sim(SimOptions::default());
+        let cfg = Config {
+            url: spawn_api(),
+            token: "token".to_string(),
+            application_id: "1".to_string(),
+            device_profile_id: "profile".to_string(),
+        };
+        let opts = ProvisionOptions {
+            baud: Some(115200),
+            module: Some(ModuleKind::Rak811),
+            name: String::new(),
+            description: String::new(),
+            dev_eui: String::new(),
+            app_key: String::new(),
+            hw_dev_eui: false,
+            activation: JoinMode::Otaa,
+        };
+        let device = provision::provision(&ureq::agent(), &cfg, &path, &opts).unwrap();
+        let mut modem =
+            crate::serial::open_modem(&path, opts.baud, opts.module, Duration::from_secs(5))
+                .unwrap();
+        let status = modem.status().unwrap();
+        assert_eq!(status.dev_eui, Some(device.dev_eui));
+        assert_eq!(status.app_key, Some(device.app_key));
+    }
+}
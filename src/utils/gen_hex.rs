@@ -31,6 +31,10 @@ pub fn get_rand_app_key() -> String {
     get_rand_hex_str(128)
 }
 
+pub fn get_rand_dev_addr() -> String {
+    get_rand_hex_str(32)
+}
+
 fn allow_char(c: char) -> bool {
     match c {
         '0'..='9' | 'a'..='f' | 'A'..='F' => return true,
@@ -82,3 +86,19 @@ where
 {
     verify_hex_str(str, 64)
 }
+
+/// Check the string is a valid hex AppEUI
+pub fn verify_app_eui<T>(str: &T) -> bool
+where
+    T: AsRef<str> + ?Sized,
+{
+    verify_hex_str(str, 64)
+}
+
+/// Check the string is a valid hex DevAddr
+pub fn verify_dev_addr<T>(str: &T) -> bool
+where
+    T: AsRef<str> + ?Sized,
+{
+    verify_hex_str(str, 32)
+}
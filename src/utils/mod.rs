@@ -28,3 +28,24 @@ pub fn escape_string(str: String) -> String {
     }
     return escaped_str;
 }
+
+/// The reverse of `escape_string`
+pub fn unescape_string(str: &str) -> String {
+    let mut unescaped_str = String::new();
+    let mut chars = str.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped_str.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped_str.push('\n'),
+            Some('r') => unescaped_str.push('\r'),
+            Some('t') => unescaped_str.push('\t'),
+            Some('0') => unescaped_str.push('\0'),
+            Some(c) => unescaped_str.push(c),
+            None => unescaped_str.push('\\'),
+        }
+    }
+    unescaped_str
+}
@@ -0,0 +1,59 @@
+use crate::ports;
+use crate::provision::{self, ProvisionOptions};
+use crate::user_config::Config;
+use log::info;
+use std::collections::HashSet;
+use std::thread;
+use std::time::Duration;
+
+/// How long a new port is left alone before provisioning, so the module can boot
+const SETTLE: Duration = Duration::from_secs(1);
+
+/// Provision every port matching `vid`/`pid` that shows up after start, until interrupted.
+/// Ports already attached at start are ignored. A port that disappears and
+/// shows up again is provisioned again, so a board can be retried by replugging it.
+pub fn handle_watch(
+    cfg: &Config,
+    vid: Option<u16>,
+    pid: Option<u16>,
+    interval: Duration,
+    opts: &ProvisionOptions,
+) {
+    let agent = ureq::agent();
+    let mut known: HashSet<String> = port_names(vid, pid);
+    let (mut passed, mut failed) = (0, 0);
+    info!(
+        "Watching for new serial ports, {} already attached are ignored. Press Ctrl-C to stop.",
+        known.len()
+    );
+    loop {
+        let present = port_names(vid, pid);
+        known.retain(|port| present.contains(port));
+        let mut new: Vec<String> = present.difference(&known).cloned().collect();
+        new.sort();
+        for port in new {
+            info!("New port {}", port);
+            thread::sleep(SETTLE);
+            match provision::provision(&agent, cfg, &port, opts) {
+                Ok(device) => {
+                    passed += 1;
+                    println!("PASS {} DevEUI {}", port, device.dev_eui);
+                }
+                Err(e) => {
+                    failed += 1;
+                    println!("FAIL {}: {}", port, e);
+                }
+            }
+            println!("Passed: {}, Failed: {}", passed, failed);
+            known.insert(port);
+        }
+        thread::sleep(interval);
+    }
+}
+
+fn port_names(vid: Option<u16>, pid: Option<u16>) -> HashSet<String> {
+    ports::filter_ports(vid, pid)
+        .into_iter()
+        .map(|port| port.port_name)
+        .collect()
+}